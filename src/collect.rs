@@ -0,0 +1,103 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+use spdlog::prelude::*;
+use serde_json::{json, Map, Value};
+use crate::run::config_file::Config;
+use crate::run::completion::JobStatus;
+
+#[derive(Debug)]
+pub struct JobRecord {
+    pub id: String,
+    pub repeat: usize,
+    pub status: JobStatus,
+    pub parameters: Vec<(String, String)>,
+}
+
+/// Walks `results_path` and returns one record per job folder, sorted by id.
+pub fn collect_records(config: &Config, results_path: &Path) -> Result<Vec<JobRecord>> {
+    let permutations = config.get_arguments_permutations();
+    let mut records = Vec::new();
+    for entry in fs::read_dir(results_path)
+        .with_context(|| format!("Failed to read results directory {}", results_path.display()))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let folder_name = entry.file_name();
+        let Some(id) = folder_name.to_str() else {
+            warn!("Skipping result folder with non UTF-8 name: {:?}", folder_name);
+            continue;
+        };
+        // Ids of the current grid map back to exact values, even if they had
+        // to be sanitized; older folders are parsed from their name
+        let known = permutations.get(id).map(|p| (p.arguments.clone(), p.repeat));
+        let Some((parameters, repeat)) = known.or_else(|| config.parse_permutation_id(id)) else {
+            warn!("Skipping result folder {} that does not match the arguments of {}", id, config.name);
+            continue;
+        };
+        records.push(JobRecord {
+            id: id.to_string(),
+            repeat,
+            status: JobStatus::from_result_dir(&entry.path()),
+            parameters,
+        });
+    }
+    records.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(records)
+}
+
+pub fn write_csv(records: &[JobRecord], argument_names: &[String], path: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut header = vec!["id", "repeat", "status"];
+    header.extend(argument_names.iter().map(String::as_str));
+    writer.write_record(&header)?;
+    for record in records {
+        let mut row = vec![record.id.clone(), record.repeat.to_string(), record.status.to_string()];
+        row.extend(record.parameters.iter().map(|(_, value)| value.clone()));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_jsonl(records: &[JobRecord], path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    for record in records {
+        let parameters: Map<String, Value> = record.parameters.iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect();
+        let line = json!({
+            "id": record.id,
+            "repeat": record.repeat,
+            "status": record.status.to_string(),
+            "parameters": parameters,
+        });
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Aggregates `<output>/<name>/*` into `<output>/<name>.csv` and `<output>/<name>.jsonl`.
+pub fn collect(config: &Config, output: &Path) -> Result<()> {
+    let results_path = output.join(&config.name);
+    let records = collect_records(config, &results_path)?;
+    let argument_names = config.argument_names();
+
+    let csv_path = output.join(format!("{}.csv", config.name));
+    write_csv(&records, &argument_names, &csv_path)?;
+    let jsonl_path = output.join(format!("{}.jsonl", config.name));
+    write_jsonl(&records, &jsonl_path)?;
+
+    let succeeded = records.iter().filter(|r| r.status == JobStatus::Succeeded).count();
+    let failed = records.iter().filter(|r| r.status == JobStatus::Failed).count();
+    let timed_out = records.iter().filter(|r| r.status == JobStatus::TimedOut).count();
+    info!("Collected {} jobs ({} succeeded, {} failed, {} timed out) into {} and {}",
+        records.len(), succeeded, failed, timed_out, csv_path.display(), jsonl_path.display());
+    Ok(())
+}
@@ -1,18 +1,32 @@
 mod run;
+mod collect;
+mod status;
+mod plan;
 
 use spdlog::prelude::*;
 use clap::{Parser, Subcommand};
 use anyhow::{Context, Result};
 use std::sync::Arc;
 use futures::future::{join_all};
-use tokio::process::{Command};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::fs;
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
-use crossbeam_queue::ArrayQueue;
-use crate::run::config_file::Permutation;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use crate::run::backend::{self, Disconnected, JobOutcome, JobRun, Scratch};
+use crate::run::config_file::{Backend, ConfigFormat, NodeCapacity};
+use crate::run::job::Job;
+use crate::run::scheduler::{self, CostModel, Scheduler};
+use crate::run::commands;
+use crate::run::node::NodeCommon;
+use crate::run::journal::{Event, Journal, Replay};
+use crate::run::shutdown::Shutdown;
+use crate::run::completion::{self, JobStatus, SkipPolicy};
+
+/// How long an idle worker waits before looking at the queue again
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 #[derive(Parser, Debug)]
 #[command(author = "Georgios Constantinides", version = "0.0.1", about = "Run experiments with a permutation of different parameters on multiple ssh nodes", long_about = None)]
@@ -28,223 +42,479 @@ enum Commands {
         config: String,
         #[arg(default_value = "results")]
         output: String,
+        /// Private keys tried for every host, before the ones from ssh_config
         #[arg(short, long)]
-        ssh_keys: Vec<String>,
+        ssh_keys: Vec<PathBuf>,
+        /// Config file format; detected from the file extension if omitted
+        #[arg(long, value_enum)]
+        format: Option<ConfigFormat>,
+        /// Which finished jobs of a previous run are not run again
+        #[arg(long, value_enum, default_value_t = SkipPolicy::Succeeded)]
+        skip: SkipPolicy,
+        /// Accept any SSH host key without checking known_hosts. Only for throwaway machines
+        #[arg(long)]
+        insecure_accept_host_keys: bool,
+        /// Print the jobs, their commands and the slots per host without running anything
+        #[arg(long)]
+        dry_run: bool,
+        /// With --dry-run, connect to the hosts to learn their threads and memory
+        #[arg(long, requires = "dry_run")]
+        check: bool,
+    },
+    Collect {
+        #[arg(default_value = "experiment.toml")]
+        config: String,
+        #[arg(default_value = "results")]
+        output: String,
+        /// Config file format; detected from the file extension if omitted
+        #[arg(long, value_enum)]
+        format: Option<ConfigFormat>,
+    },
+    /// Shows how many jobs of an experiment are pending, running or finished
+    Status {
+        #[arg(default_value = "experiment.toml")]
+        config: String,
+        #[arg(default_value = "results")]
+        output: String,
+        /// Config file format; detected from the file extension if omitted
+        #[arg(long, value_enum)]
+        format: Option<ConfigFormat>,
+        /// Connect to the SSH hosts or the Slurm login node to poll jobs left running detached
+        #[arg(long)]
+        check: bool,
+        /// Private keys tried for every host, before the ones from ssh_config
+        #[arg(short, long)]
+        ssh_keys: Vec<PathBuf>,
+        /// Accept any SSH host key without checking known_hosts. Only for throwaway machines
+        #[arg(long)]
+        insecure_accept_host_keys: bool,
     },
-    Collect,
-}
-struct SshAgent{
-    pid: u32,
-}
-
-impl Drop for SshAgent {
-    fn drop(&mut self) {
-        info!("Shutting down ssh-agent (PID: {})...", self.pid);
-        let _ = std::process::Command::new("kill")
-            .arg(self.pid.to_string())
-            .status();
-    }
-}
-
-async unsafe fn setup_ssh_agent(keys: &[String]) -> Result<SshAgent> {
-    let agent_output = Command::new("ssh-agent")
-        .arg("-s")
-        .output()
-        .await
-        .context("Failed to start ssh-agent.")?;
-    
-    if !agent_output.status.success() {
-        anyhow::bail!("ssh-agent exited with an error: {}", String::from_utf8_lossy(&agent_output.stderr));
-    }
-
-    let stdout = String::from_utf8(agent_output.stdout)?;
-    let mut agent_pid = None;
-    for line in stdout.lines() {
-        if let Some(var_line) = line.split(';').next() {
-            if let Some((key, value)) = var_line.split_once('=') {
-                unsafe {
-                    std::env::set_var(key, value);
-                }
-                if key == "SSH_AGENT_PID" {
-                    agent_pid = value.parse::<u32>().ok();
-                }
-            }
-        }
-    }
-
-    let pid = agent_pid.context("Could not parse SSH_AGENT_PID from ssh-agent output.")?;
-    info!("ssh-agent started with PID: {}", pid);
-
-    let mut cmd = Command::new("ssh-add");
-    if keys.is_empty() {
-        warn!("No SSH keys specified, attempting to add default identities...");
-    } else {
-        info!("Adding specified SSH keys to agent: {:?}", keys);
-        cmd.args(keys);
-    }
-    let add_status = cmd.status().await.context("Failed to execute ssh-add.")?;
-    if !add_status.success() {
-        anyhow::bail!("ssh-add failed");
-    }
-
-    Ok(SshAgent { pid })
 }
-
 #[tokio::main]
 async fn main() -> Result<()> {
+    // rsync runs ssh with this program as SSH_ASKPASS to pass on a passphrase
+    if let Some(secret_file) = std::env::var_os(commands::ASKPASS_FILE_VAR) {
+        println!("{}", fs::read_to_string(secret_file)?);
+        return Ok(());
+    }
     let args = Args::parse();
     spdlog::default_logger().set_level_filter(spdlog::LevelFilter::All);
     match args.command {
-        Commands::Run { config, output, ssh_keys } => unsafe {
-            let _agent = setup_ssh_agent(&ssh_keys).await?;
-
+        Commands::Run { config, output, ssh_keys, format, skip, insecure_accept_host_keys, dry_run, check } => {
             debug!("Running with config: {} and output:{}", config, output);
-            let config_struct = run::config_file::Config::new(&config);
+            let config_struct = run::config_file::Config::new(&config, format)?;
             debug!("Loaded config: {:?}", config_struct);
+            if dry_run {
+                let connect = check.then(|| NodeCommon::new(insecure_accept_host_keys, ssh_keys, config_struct.auth.clone()));
+                return plan::plan(&config_struct, Path::new(&output), skip, connect).await;
+            }
             let mut permutations = config_struct.get_arguments_permutations();
             debug!("Permutations: {:?}", permutations);
-            let results_path_string = format!("./results/{}", &config_struct.name);
-            let results_path = Path::new(results_path_string.as_str());
+            let results_path_buf = Path::new(&output).join(&config_struct.name);
+            let results_path = results_path_buf.as_path();
             match fs::create_dir_all(results_path){
                 Ok(_) => {
-                    for entry in fs::read_dir(results_path)? {
-                        let valid_entry = entry.expect("Error reading result folder name");
-                        let folder_name = valid_entry.file_name();
-                        let folder_name_str = folder_name.to_str().expect("Failed to convert results folder name to string");
-                        if permutations.contains_key(folder_name_str) && valid_entry.path().join("complete").exists() {
-                            permutations.remove(folder_name_str).expect("Failed to remove found permutation");
-                        }
-                    }
+                    completion::filter_finished(results_path, &mut permutations, skip)?;
                 let total_jobs = permutations.len();
-                let queue = Arc::new(ArrayQueue::<Permutation>::new(permutations.len()));
                 let failed_count = Arc::new(AtomicUsize::new(0));
-                    for permutation in permutations {
-                        queue.push(Permutation {
-                            id: permutation.0,
-                            parameters: permutation.1
-                        }).expect("Task queue full. This should not have happened");
+                let timed_out_count = Arc::new(AtomicUsize::new(0));
+
+                    let mut jobs = Vec::with_capacity(total_jobs);
+                    for permutation in permutations.into_values() {
+                        let threads = config_struct.threads_for(&permutation)?;
+                        let memory = config_struct.memory_for(&permutation)?;
+                        jobs.push(Job::new(permutation, threads, memory));
+                    }
+                    let replay = Replay::load(results_path)?;
+                    for job in &mut jobs {
+                        if let Some(attempt) = replay.attempts.get(&job.permutation.id) {
+                            job.attempt = *attempt;
+                        }
+                        job.reattach_host = replay.running.get(&job.permutation.id).cloned();
+                    }
+                    if !replay.running.is_empty() {
+                        info!("{} jobs were still running when the last run stopped, reattaching to them", replay.running.len());
                     }
+                    let min_job_threads = run::config_file::min_job_threads(jobs.iter().map(|job| job.threads));
+                    let max_job_threads = jobs.iter().map(|job| job.threads).max().unwrap_or(1);
 
-                    let nodes = run::nodes::Nodes::new(&config_struct.hosts).await.expect("Failed to connect to nodes");
+                    let scratch = Scratch::for_config(&config_struct);
+                    let nodes = match config_struct.backend {
+                        Backend::Ssh => {
+                            let node_common = NodeCommon::new(insecure_accept_host_keys, ssh_keys, config_struct.auth.clone());
+                            run::nodes::Nodes::new(&config_struct.hosts, config_struct.min_nodes, node_common, &scratch).await?
+                        }
+                        Backend::Local => run::nodes::Nodes::local(&scratch)?,
+                        Backend::Slurm => {
+                            let slurm = config_struct.slurm.clone().context("backend = \"slurm\" needs a [slurm] section")?;
+                            let node_common = NodeCommon::new(insecure_accept_host_keys, ssh_keys, config_struct.auth.clone());
+                            run::nodes::Nodes::slurm(slurm, node_common, &scratch, max_job_threads).await?
+                        }
+                    };
+                    let node_count = nodes.nodes.len();
+                    let retry_backoff = Duration::from_secs(config_struct.retry_backoff);
 
-                    let temp_path_string = format!("/tmp/MNER/{}", &config_struct.name);
-                    let temp_path = Path::new(temp_path_string.as_str());
-                    let temp_workdir =  temp_path.join("workdir");
-                    let temp_workdir_str = temp_workdir.to_str().expect("failed to create path string for workdir");
-                    let temp_results_path = temp_path.join("results");
-                    let temp_results_path = &temp_results_path;
-                    let temp_workdir_executable_path = temp_workdir.join(&config_struct.executable);
-                    let temp_workdir_executable_str = temp_workdir_executable_path.to_str().expect("failed to create temp workdir executable path string");
+                    let capacities: Vec<NodeCapacity> = nodes.nodes.iter()
+                        .map(|node| config_struct.node_capacity(node.name(), node.threads(), node.memory(), min_job_threads, node.max_slots()))
+                        .collect();
+                    for (node, capacity) in nodes.nodes.iter().zip(&capacities) {
+                        match capacity.memory {
+                            Some(memory) => debug!("{} runs up to {} jobs on {} threads and {} MiB", node.name(), capacity.slots, capacity.threads, memory),
+                            None => debug!("{} runs up to {} jobs on {} threads", node.name(), capacity.slots, capacity.threads),
+                        }
+                    }
+                    // Threads in use on each node, so that jobs needing many threads
+                    // wait for others to finish instead of oversubscribing the node
+                    let thread_budgets: Vec<Semaphore> = capacities.iter().map(|capacity| Semaphore::new(capacity.threads)).collect();
+                    // Memory in use on each node whose memory is known, in MiB
+                    let memory_budgets: Vec<Option<Semaphore>> = capacities.iter()
+                        .map(|capacity| capacity.memory.map(|memory| Semaphore::new(memory.min(Semaphore::MAX_PERMITS as u64) as usize)))
+                        .collect();
+                    let slots: Vec<usize> = capacities.iter().map(|capacity| capacity.slots).collect();
+                    // A job may still be running on a host that is not part of this
+                    // run; starting it again could run it twice, so it is left to a
+                    // later run that can reattach to it
+                    let left_count = AtomicUsize::new(0);
+                    jobs.retain(|job| match &job.reattach_host {
+                        Some(host) if !nodes.nodes.iter().any(|node| node.name() == host) => {
+                            warn!("Cannot reattach to task {} since {} is not part of this run, leaving it for a later run", job.permutation.id, host);
+                            left_count.fetch_add(1, Ordering::Relaxed);
+                            false
+                        }
+                        _ => true,
+                    });
+                    let costs = CostModel::load(results_path, &config_struct);
+                    let scheduler = Arc::new(Scheduler::new(jobs, &costs, &slots));
 
+                    let journal = Journal::open(results_path)?;
+                    journal.record(Event::RunStarted { jobs: total_jobs });
+                    let journal = &journal;
+                    let shutdown = Shutdown::listen()?;
+                    let shutdown = &shutdown;
                     let config_struct = &config_struct;
-                    let queue = queue.clone();
+                    let slots = &slots;
+                    let capacities = &capacities;
+                    let thread_budgets = &thread_budgets;
+                    let memory_budgets = &memory_budgets;
+                    // Nodes whose workdir could not be prepared run no workers
+                    let down: Vec<AtomicBool> = nodes.nodes.iter().map(|_| AtomicBool::new(false)).collect();
+                    let down = &down;
+                    let left_count = &left_count;
+                    let host_names: Vec<&str> = nodes.nodes.iter().map(|node| node.name()).collect();
+                    let host_names = &host_names;
+                    // Jobs needing more memory than their node has wait for a larger
+                    // one, and fail if no node that is still up has enough
+                    let largest_memory = || capacities.iter().zip(down)
+                        .filter(|(_, down)| !down.load(Ordering::Acquire))
+                        .map(|(capacity, _)| capacity.memory.unwrap_or(u64::MAX))
+                        .max()
+                        .unwrap_or(u64::MAX);
                     let failed_count = failed_count.clone();
-                    let node_futures = nodes.nodes.iter().map(|node| {
-                        let queue = queue.clone();
+                    let timed_out_count = timed_out_count.clone();
+                    let node_futures = nodes.nodes.iter().enumerate().map(|(node_index, node)| {
+                        let scheduler = scheduler.clone();
                         let failed_count = failed_count.clone();
+                        let timed_out_count = timed_out_count.clone();
                         async move {
-                        debug!("Syncing {} to {}", &config_struct.workdir,  node.hostname);
-                        match node.rsync_to(&config_struct.workdir, temp_workdir_str, false).await {
+                        debug!("Syncing {} to {}", &config_struct.workdir,  node.name());
+                        match node.prepare_workdir(&config_struct.workdir).await {
                             Ok(_) => {
-                                debug!("Synced {} to {}/workdir", &config_struct.workdir,  node.hostname);
-                                let concurrency = if config_struct.threads_per_task == 0 {1} else  {node.threads/config_struct.threads_per_task};
+                                debug!("Synced {} to {}/workdir", &config_struct.workdir,  node.name());
+                                let concurrency = slots[node_index];
                                 let mut node_worker_futures = Vec::with_capacity(concurrency);
                                 for _ in 0..concurrency{
 
-                                    let queue = queue.clone();
+                                    let scheduler = scheduler.clone();
                                     let failed_count = failed_count.clone();
+                                    let timed_out_count = timed_out_count.clone();
                                     node_worker_futures.push(async move {
                                         loop{
-                                            match queue.pop() {
-                                                None => break,
-                                                Some(permutation) => {
-                                                    let tmp_permutation_result_path = temp_results_path.join(&permutation.id);
-                                                    let tmp_permutation_result_path_str = tmp_permutation_result_path.to_str().expect("failed to create path string for job result");
-                                                    match node.client.execute(format!("mkdir -p {tmp_permutation_result_path_str} && cd {tmp_permutation_result_path_str} && {temp_workdir_executable_str} {}", permutation.parameters).as_str()).await {
-                                                        Ok(output) =>{
-                                                            let permutation_result_path = results_path.join(&permutation.id);
-                                                            let permutation_result_path_str = permutation_result_path.to_str().expect("failed to convert permutation_result_path to string");
-                                                            let _ = fs::remove_dir_all(&permutation_result_path);
-                                                            match fs::create_dir_all(&permutation_result_path){
-                                                                Ok(_)=>{
-                                                                    if output.exit_status == 0{
-                                                                        match node.rsync_from(tmp_permutation_result_path_str, permutation_result_path_str, true).await{
-                                                                            Ok(_) => {
-                                                                                if let Err(err) = File::create(permutation_result_path.join("succeeded")) {
-                                                                                    error!("failed to create \"succeeded\" file for job {}\n{}", permutation.id, err);
-                                                                                }
-                                                                            },
-                                                                            Err(err) => {
-                                                                                error!("failed to rsync completed data of task from {} to {}\n{}",tmp_permutation_result_path_str, permutation_result_path_str, err);
-                                                                                failed_count.fetch_add(1, Ordering::Relaxed);
-                                                                            }
-                                                                        }
-                                                                    }else {
-                                                                        match File::create(permutation_result_path.join("failed")){
-                                                                            Ok(_) => {}, //create empty file
-                                                                            Err(err) => error!("failed to create \"failed\" file for job {}\n{}", permutation.id, err)
+                                            if shutdown.is_draining() || down[node_index].load(Ordering::Acquire) {
+                                                break;
+                                            }
+                                            let Some(mut job) = scheduler.pop(node_index) else {
+                                                // Jobs that are still running may be put back for a retry
+                                                if scheduler.is_done() {
+                                                    break;
+                                                }
+                                                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                                                continue;
+                                            };
+                                            if !job.is_ready() || job.should_avoid(node.name(), node_count) {
+                                                if job.is_ready() {
+                                                    job.deferrals += 1;
+                                                }
+                                                scheduler.push(job);
+                                                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                                                continue;
+                                            }
+
+                                            if let Some(host) = job.reattach_host.as_deref() && host != node.name() {
+                                                let host_down = host_names.iter().zip(down)
+                                                    .any(|(name, down)| *name == host && down.load(Ordering::Acquire));
+                                                if host_down {
+                                                    warn!("Cannot reattach to task {} since {} is down, leaving it for a later run", job.permutation.id, host);
+                                                    left_count.fetch_add(1, Ordering::Relaxed);
+                                                    scheduler.finish();
+                                                    continue;
+                                                }
+                                                scheduler.push(job);
+                                                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                                                continue;
+                                            }
+                                            // A job to reattach to already runs here, and the memory it uses
+                                            // is missing from what the node reported as available
+                                            let reattach = job.reattach_host.is_some();
+                                            let node_memory = capacities[node_index].memory.unwrap_or(u64::MAX);
+                                            let largest = largest_memory();
+                                            if !reattach && job.memory > largest {
+                                                let reason = format!("task {} needs {} MiB but no node that is up has more than {} MiB", job.permutation.id, job.memory, largest);
+                                                error!("{}", reason);
+                                                failed_count.fetch_add(1, Ordering::Relaxed);
+                                                let permutation_result_path = results_path.join(&job.permutation.id);
+                                                let _ = fs::remove_dir_all(&permutation_result_path);
+                                                if let Err(err) = fs::create_dir_all(&permutation_result_path)
+                                                    .and_then(|_| fs::write(permutation_result_path.join("stderr"), reason))
+                                                    .and_then(|_| completion::mark_complete(&permutation_result_path, JobStatus::Failed)) {
+                                                    error!("failed to write completion marker for job {}\n{}", job.permutation.id, err);
+                                                } else {
+                                                    journal.record(Event::Completed { id: job.permutation.id.clone(), status: JobStatus::Failed.to_string() });
+                                                }
+                                                scheduler.finish();
+                                                continue;
+                                            }
+                                            if !reattach && job.memory > node_memory {
+                                                scheduler.push(job);
+                                                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                                                continue;
+                                            }
+                                            // Only a reattached job gets here needing more than the node has,
+                                            // and a budget never hands out more than it holds
+                                            let memory = job.memory.min(node_memory);
+                                            let acquire_memory = move || async move {
+                                                match &memory_budgets[node_index] {
+                                                    Some(budget) => Some(budget.acquire_many(memory.min(u32::MAX as u64) as u32).await
+                                                        .expect("memory budget semaphore closed")),
+                                                    None => None,
+                                                }
+                                            };
+                                            let _memory_permit = if reattach { None } else { acquire_memory().await };
+                                            // A job with threads_per_task = 0 takes all of the node's threads,
+                                            // unless the backend counts jobs rather than threads
+                                            let threads = match job.threads {
+                                                0 if node.max_slots().is_some() => 0,
+                                                0 => capacities[node_index].threads,
+                                                threads => threads.min(capacities[node_index].threads),
+                                            };
+                                            if threads < job.threads {
+                                                warn!("task {} needs {} threads but {} has only {}", job.permutation.id, job.threads, node.name(), threads);
+                                            }
+                                            let _threads_permit = thread_budgets[node_index].acquire_many(threads as u32).await
+                                                .expect("thread budget semaphore closed");
+                                            // The wait for resources may have outlasted the run
+                                            if shutdown.is_draining() {
+                                                scheduler.push(job);
+                                                break;
+                                            }
+                                            let permutation = &job.permutation;
+                                            let can_retry = job.attempt < config_struct.retries;
+                                            let mut retry = false;
+                                            let job_run = JobRun::new(config_struct, permutation, threads, job.memory);
+                                            journal.record(Event::Dispatched { id: permutation.id.clone(), host: node.name().to_string(), attempt: job.attempt });
+                                            let run = async {
+                                                if reattach && let Some(outcome) = node.reattach(&job_run).await? {
+                                                    return Ok(outcome);
+                                                }
+                                                // Started from scratch after all, so it needs its memory
+                                                let _memory_permit = if reattach { acquire_memory().await } else { None };
+                                                node.run_job(&job_run).await
+                                            };
+                                            let started = Instant::now();
+                                            let outcome = tokio::select! {
+                                                outcome = run => outcome,
+                                                _ = shutdown.aborted() => {
+                                                    warn!("killing task {} on {}", permutation.id, node.name());
+                                                    if let Err(err) = node.kill_job(&job_run).await {
+                                                        error!("failed to kill task {} on {}\n{}", permutation.id, node.name(), err);
+                                                    }
+                                                    journal.record(Event::Killed { id: permutation.id.clone(), host: node.name().to_string() });
+                                                    scheduler.push(job);
+                                                    break;
+                                                }
+                                            };
+                                            let elapsed = started.elapsed();
+                                            let disconnected = outcome.as_ref().is_err_and(|err| err.is::<Disconnected>());
+                                            // Detached and Slurm jobs outlive the connection, so rerunning
+                                            // them would run them twice; they are reattached to instead
+                                            if disconnected && (job_run.detached || config_struct.backend == Backend::Slurm) {
+                                                warn!("lost track of task {} on {}, it will be reattached to", permutation.id, node.name());
+                                                job.reattach_host = Some(node.name().to_string());
+                                                scheduler.push(job);
+                                                if !backend::reconnect(node.as_ref()).await {
+                                                    error!("{} is unreachable, no more tasks are started on it", node.name());
+                                                    down[node_index].store(true, Ordering::Release);
+                                                    break;
+                                                }
+                                                continue;
+                                            }
+                                            let (id, host) = (permutation.id.clone(), node.name().to_string());
+                                            journal.record(match &outcome {
+                                                Ok(JobOutcome::TimedOut) => Event::TimedOut { id, host },
+                                                Ok(JobOutcome::Exited(output)) => Event::Exited { id, host, exit_status: output.exit_status },
+                                                Err(err) => Event::Errored { id, host, error: format!("{:#}", err) },
+                                            });
+                                            match outcome {
+                                                Ok(JobOutcome::TimedOut) => {
+                                                    warn!("task {} timed out on {} and was killed", permutation.id, node.name());
+                                                    timed_out_count.fetch_add(1, Ordering::Relaxed);
+                                                    let permutation_result_path = results_path.join(&permutation.id);
+                                                    let _ = fs::remove_dir_all(&permutation_result_path);
+                                                    if let Err(err) = fs::create_dir_all(&permutation_result_path)
+                                                        .and_then(|_| {
+                                                            scheduler::record_duration(&permutation_result_path, elapsed);
+                                                            completion::mark_complete(&permutation_result_path, JobStatus::TimedOut)
+                                                        }) {
+                                                        error!("failed to write completion marker for job {}\n{}", permutation.id, err);
+                                                    } else {
+                                                        journal.record(Event::Completed { id: permutation.id.clone(), status: JobStatus::TimedOut.to_string() });
+                                                    }
+                                                },
+                                                Ok(JobOutcome::Exited(output)) if output.exit_status != 0 && config_struct.retry_failed_jobs && can_retry => {
+                                                    warn!("task {} exited with status {} on {}, it will be retried", permutation.id, output.exit_status, node.name());
+                                                    retry = true;
+                                                },
+                                                Ok(JobOutcome::Exited(output)) =>{
+                                                    let permutation_result_path = results_path.join(&permutation.id);
+                                                    let _ = fs::remove_dir_all(&permutation_result_path);
+                                                    match fs::create_dir_all(&permutation_result_path){
+                                                        Ok(_)=>{
+                                                            let status = if output.exit_status == 0{
+                                                                let fetched = node.fetch_results(&permutation.id, &permutation_result_path).await;
+                                                                journal.record(Event::Transferred {
+                                                                    id: permutation.id.clone(),
+                                                                    host: node.name().to_string(),
+                                                                    error: fetched.as_ref().err().map(|err| format!("{:#}", err)),
+                                                                });
+                                                                match fetched {
+                                                                    Ok(_) => Some(JobStatus::Succeeded),
+                                                                    Err(err) => {
+                                                                        error!("failed to fetch the results of task {} from {}\n{}", permutation.id, node.name(), err);
+                                                                        if can_retry {
+                                                                            retry = true;
+                                                                        } else {
+                                                                            failed_count.fetch_add(1, Ordering::Relaxed);
                                                                         }
-                                                                        failed_count.fetch_add(1, Ordering::Relaxed);
+                                                                        None
                                                                     }
+                                                                }
+                                                            }else {
+                                                                failed_count.fetch_add(1, Ordering::Relaxed);
+                                                                Some(JobStatus::Failed)
+                                                            };
 
-                                                                    match File::create(permutation_result_path.join("stdout")) {
-                                                                        Ok(mut file) => {
-                                                                            if let Err(err) = file.write(output.stdout.as_bytes()){
-                                                                                error!("failed to write data to stdout file for {}\n{}", permutation.id, err);
-                                                                            }
-                                                                        },
-                                                                        Err(err) => error!("failed to create stdout file for {}\n{}", permutation.id, err)
+                                                            // Written after fetching, which replaces the folder
+                                                            scheduler::record_duration(&permutation_result_path, elapsed);
+                                                            match File::create(permutation_result_path.join("stdout")) {
+                                                                Ok(mut file) => {
+                                                                    if let Err(err) = file.write(output.stdout.as_bytes()){
+                                                                        error!("failed to write data to stdout file for {}\n{}", permutation.id, err);
                                                                     }
-                                                                    match File::create(permutation_result_path.join("stderr")) {
-                                                                        Ok(mut file) => {
-                                                                            if let Err(err) = file.write(output.stderr.as_bytes()){
-                                                                                error!("failed to write data to stderr file for {}\n{}", permutation.id, err);
-                                                                            }
-                                                                        },
-                                                                        Err(err) => error!("failed to create stderr file for {}\n{}", permutation.id, err)
+                                                                },
+                                                                Err(err) => error!("failed to create stdout file for {}\n{}", permutation.id, err)
+                                                            }
+                                                            match File::create(permutation_result_path.join("stderr")) {
+                                                                Ok(mut file) => {
+                                                                    if let Err(err) = file.write(output.stderr.as_bytes()){
+                                                                        error!("failed to write data to stderr file for {}\n{}", permutation.id, err);
                                                                     }
                                                                 },
-                                                                Err(err) => {
-                                                                    error!("failed to create result for job: {}\n{}", permutation.id, err);
-                                                                    failed_count.fetch_add(1, Ordering::Relaxed);
+                                                                Err(err) => error!("failed to create stderr file for {}\n{}", permutation.id, err)
+                                                            }
+                                                            if let Some(status) = status {
+                                                                match completion::mark_complete(&permutation_result_path, status) {
+                                                                    Ok(_) => journal.record(Event::Completed { id: permutation.id.clone(), status: status.to_string() }),
+                                                                    Err(err) => error!("failed to write completion marker for job {}\n{}", permutation.id, err),
                                                                 }
                                                             }
                                                         },
                                                         Err(err) => {
-                                                            error!("failed to execute task {} on {}\n{}", permutation.id, node.hostname, err);
+                                                            error!("failed to create result for job: {}\n{}", permutation.id, err);
                                                             failed_count.fetch_add(1, Ordering::Relaxed);
                                                         }
                                                     }
+                                                },
+                                                Err(err) => {
+                                                    error!("failed to execute task {} on {}\n{}", permutation.id, node.name(), err);
+                                                    if can_retry {
+                                                        retry = true;
+                                                    } else {
+                                                        failed_count.fetch_add(1, Ordering::Relaxed);
+                                                    }
                                                 }
                                             }
+
+                                            if retry {
+                                                info!("retrying task {} (attempt {} of {})", job.permutation.id, job.attempt + 2, config_struct.retries + 1);
+                                                journal.record(Event::Retried { id: job.permutation.id.clone(), attempt: job.attempt + 1 });
+                                                scheduler.push(job.retry(node.name(), retry_backoff));
+                                            } else {
+                                                scheduler.finish();
+                                            }
+                                            if disconnected && !backend::reconnect(node.as_ref()).await {
+                                                error!("{} is unreachable, no more tasks are started on it", node.name());
+                                                down[node_index].store(true, Ordering::Release);
+                                                break;
+                                            }
                                         }
                                     });
                                 }
                                 join_all(node_worker_futures).await;
                             },
-                            Err(err) => error!("Failed to rsync data to host {}. It will be skipped\n{}", node.hostname, err),
+                            Err(err) => {
+                                down[node_index].store(true, Ordering::Release);
+                                error!("Failed to rsync data to host {}. It will be skipped\n{}", node.name(), err);
+                            }
                         }
                     }
                     });
                     join_all(node_futures).await;
 
                     let cleanup_futures = nodes.nodes.iter().map(|node| async {
-                        match node.rm(temp_path_string.as_str()).await {
-                            Ok(_) => debug!("Removed {} from {}", temp_path_string, node.hostname),
-                            Err(err) => debug!("Failed to remove {} from {}\n{}", temp_path_string, node.hostname, err)
+                        match node.cleanup().await {
+                            Ok(_) => debug!("Removed {} from {}", scratch.root(), node.name()),
+                            Err(err) => debug!("Failed to remove {} from {}\n{}", scratch.root(), node.name(), err)
                         }
                     });
                     join_all(cleanup_futures).await;
+                    let unfinished = scheduler.drain();
+                    let left = left_count.load(Ordering::Relaxed);
+                    // Jobs left running elsewhere are still to be reattached to
+                    journal.record(Event::RunEnded { interrupted: shutdown.is_draining() || left > 0 || !unfinished.is_empty() });
+                    if left > 0 {
+                        warn!("{} jobs may still be running on hosts that were not available, run again once they are back to reattach", left);
+                    }
+                    if shutdown.is_draining() {
+                        info!("Run interrupted with {} jobs unfinished, run it again to resume", unfinished.len());
+                    } else if !unfinished.is_empty() {
+                        warn!("{} jobs were not run since no node was left for them, run again to resume", unfinished.len());
+                    }
                     let failed = failed_count.load(Ordering::Relaxed);
-                    let succeeded = total_jobs - failed - queue.len();
-                    info!("{}/{} failed: {}", succeeded, total_jobs, failed);
+                    let timed_out = timed_out_count.load(Ordering::Relaxed);
+                    let succeeded = total_jobs - failed - timed_out - unfinished.len() - left;
+                    info!("{}/{} failed: {} timed out: {}", succeeded, total_jobs, failed, timed_out);
                 },
                 Err(err) => error!("Failed to create results directory\n{}", err),
             }
 
         }
-        Commands::Collect => {
-            println!("TODO: implement Collect");
+        Commands::Collect { config, output, format } => {
+            let config_struct = run::config_file::Config::new(&config, format)?;
+            collect::collect(&config_struct, Path::new(&output))?;
+        }
+        Commands::Status { config, output, format, check, ssh_keys, insecure_accept_host_keys } => {
+            let config_struct = run::config_file::Config::new(&config, format)?;
+            let connect = check.then(|| NodeCommon::new(insecure_accept_host_keys, ssh_keys, config_struct.auth.clone()));
+            status::status(&config_struct, Path::new(&output), connect).await?;
         }
     }
 
@@ -0,0 +1,129 @@
+use std::path::Path;
+use anyhow::{Context, Result};
+use spdlog::prelude::*;
+use crate::run::backend::{JobRun, Scratch};
+use crate::run::completion::{self, SkipPolicy};
+use crate::run::config_file::{self, Backend, Config};
+use crate::run::node::NodeCommon;
+use crate::run::nodes::Nodes;
+
+/// Threads and memory of a host as far as they are known without a run.
+struct HostResources {
+    name: String,
+    threads: Option<usize>,
+    memory: Option<u64>,
+    max_slots: Option<usize>,
+}
+
+/// Prints what `Run` would do for `config` with results in `output`: the
+/// jobs with their commands, the jobs that `skip` leaves out and the slots
+/// of every host. Nothing is written. Hosts are only contacted if `connect`
+/// is given, to learn their threads and memory; otherwise they are taken
+/// from `host_settings` where set.
+pub async fn plan(config: &Config, output: &Path, skip: SkipPolicy, connect: Option<NodeCommon>) -> Result<()> {
+    let results_path = output.join(&config.name);
+    let mut permutations = config.get_arguments_permutations();
+    let skipped = completion::skipped(&results_path, &permutations, skip);
+    for (id, _) in &skipped {
+        permutations.remove(id);
+    }
+    let mut permutations: Vec<_> = permutations.into_values().collect();
+    permutations.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let scratch = Scratch::for_config(config);
+    let mut job_threads = Vec::with_capacity(permutations.len());
+    let mut max_job_threads = 1;
+    println!("{}: {} jobs to run, {} skipped", config.name, permutations.len(), skipped.len());
+    println!();
+    println!("Jobs:");
+    for permutation in &permutations {
+        let threads = config.threads_for(permutation)?;
+        let memory = config.memory_for(permutation)?;
+        job_threads.push(threads);
+        max_job_threads = max_job_threads.max(threads);
+        let job = JobRun::new(config, permutation, threads, memory);
+        let mut resources = match threads {
+            0 => "all threads of a node".to_string(),
+            threads => format!("{} threads", threads),
+        };
+        if let Some(memory) = job.memory {
+            resources.push_str(&format!(", {} MiB", memory));
+        }
+        if let Some(timeout) = job.timeout {
+            resources.push_str(&format!(", timeout {}s", timeout.as_secs()));
+        }
+        let command = if job.detached && config.backend != Backend::Slurm {
+            scratch.detached_command(&job)
+        } else {
+            scratch.job_command(&job)
+        };
+        println!("  {} ({})", permutation.id, resources);
+        println!("    {}", command);
+    }
+    if !skipped.is_empty() {
+        println!();
+        println!("Skipped:");
+        for (id, status) in &skipped {
+            println!("  {} ({})", id, status);
+        }
+    }
+
+    let min_job_threads = config_file::min_job_threads(job_threads);
+    let hosts = host_resources(config, &scratch, connect, max_job_threads).await?;
+    println!();
+    println!("Projected slots:");
+    let width = hosts.iter().map(|host| host.name.len()).max().unwrap_or(0);
+    for host in &hosts {
+        let Some(threads) = host.threads else {
+            println!("  {:<width$}  unknown, set host_settings.threads or pass --check", host.name);
+            continue;
+        };
+        let capacity = config.node_capacity(&host.name, threads, host.memory, min_job_threads, host.max_slots);
+        let memory = capacity.memory.map(|memory| format!(", {} MiB", memory)).unwrap_or_default();
+        println!("  {:<width$}  {} slots ({} threads{})", host.name, capacity.slots, capacity.threads, memory);
+    }
+    Ok(())
+}
+
+async fn host_resources(config: &Config, scratch: &Scratch, connect: Option<NodeCommon>, max_job_threads: usize) -> Result<Vec<HostResources>> {
+    let from_nodes = |nodes: Nodes| nodes.nodes.iter()
+        .map(|node| HostResources { name: node.name().to_string(), threads: Some(node.threads()), memory: node.memory(), max_slots: node.max_slots() })
+        .collect();
+    match config.backend {
+        Backend::Local => Ok(from_nodes(Nodes::local(scratch)?)),
+        Backend::Slurm => {
+            let slurm = config.slurm.as_ref().context("backend = \"slurm\" needs a [slurm] section")?;
+            let login = slurm.login_host.as_deref().unwrap_or("localhost");
+            Ok(vec![HostResources {
+                name: format!("slurm@{}", login),
+                threads: Some(slurm.max_jobs * max_job_threads),
+                memory: None,
+                max_slots: Some(slurm.max_jobs),
+            }])
+        }
+        Backend::Ssh => match connect {
+            Some(common) => {
+                let nodes = Nodes::new(&config.hosts, 0, common, scratch).await?;
+                let mut hosts: Vec<HostResources> = from_nodes(nodes);
+                for host in &config.hosts {
+                    if !hosts.iter().any(|reached| &reached.name == host) {
+                        warn!("{} is unreachable and left out of the projection", host);
+                    }
+                }
+                hosts.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(hosts)
+            }
+            None => Ok(config.hosts.iter()
+                .map(|host| {
+                    let settings = config.host_settings.get(host);
+                    HostResources {
+                        name: host.clone(),
+                        threads: settings.and_then(|settings| settings.threads),
+                        memory: settings.and_then(|settings| settings.memory),
+                        max_slots: None,
+                    }
+                })
+                .collect()),
+        },
+    }
+}
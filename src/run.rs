@@ -1,4 +1,15 @@
+pub mod backend;
 pub mod config_file;
+pub mod completion;
+pub mod job;
+pub mod local;
+pub mod detached;
 pub mod node;
 pub mod nodes;
-mod commands;
\ No newline at end of file
+mod host_keys;
+mod ssh_options;
+pub mod scheduler;
+pub mod shutdown;
+pub mod journal;
+pub mod slurm;
+pub mod commands;
\ No newline at end of file
@@ -0,0 +1,424 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use spdlog::prelude::*;
+use super::commands::shell_quote;
+use super::config_file::{Backend as BackendKind, Config, ContainerConfig, ContainerRuntime, Permutation};
+
+/// A job as handed to a backend.
+pub struct JobRun<'a> {
+	/// Permutation id, which names the job's directories
+	pub id: &'a str,
+	/// Path of the executable relative to the workdir
+	pub executable: &'a str,
+	pub argv: &'a [String],
+	pub timeout: Option<Duration>,
+	/// Hardware threads the job may use, 0 for all of the node's where the
+	/// backend picks the node
+	pub threads: usize,
+	/// Memory in MiB the job needs, if configured
+	pub memory: Option<u64>,
+	/// Image and settings to run the executable in a container with
+	pub container: Option<&'a ContainerConfig>,
+	/// Start the job detached from the controller, see [`detached`](super::detached)
+	pub detached: bool,
+}
+
+impl<'a> JobRun<'a> {
+	/// `permutation` of `config` with `threads` threads and `memory` MiB.
+	pub fn new(config: &'a Config, permutation: &'a Permutation, threads: usize, memory: u64) -> Self {
+		Self {
+			id: &permutation.id,
+			executable: &config.executable,
+			argv: &permutation.argv,
+			timeout: config.timeout_for(permutation),
+			threads,
+			memory: config.memory_per_task.is_some().then_some(memory),
+			container: config.container.as_ref(),
+			detached: config.detached,
+		}
+	}
+}
+
+pub struct JobOutput {
+	pub exit_status: u32,
+	pub stdout: String,
+	pub stderr: String,
+}
+
+pub enum JobOutcome {
+	Exited(JobOutput),
+	/// The job ran into its timeout and was killed
+	TimedOut,
+}
+
+/// The connection to a node broke while a command ran on it. Jobs started
+/// detached from the connection may still be running.
+#[derive(Debug, thiserror::Error)]
+#[error("lost the connection to {host}")]
+pub struct Disconnected {
+	pub host: String,
+	#[source]
+	pub source: async_ssh2_tokio::Error,
+}
+
+/// Longest a job is given to exit after SIGTERM before it gets SIGKILL
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How often [`reconnect`] tries before it gives up
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// Pause before the first attempt to reconnect, doubled for every further one
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Somewhere jobs run, such as a host reached over SSH.
+///
+/// The scheduler calls [`prepare_workdir`](Backend::prepare_workdir) once,
+/// then runs as many jobs at a time as its threads allow, less what the
+/// host settings reserve, and fetches the results of every job that exited
+/// successfully. `cleanup` is called when no jobs are left.
+#[async_trait]
+pub trait Backend: Send + Sync {
+	/// Name in log messages, also used to move retries to other nodes
+	fn name(&self) -> &str;
+
+	/// Number of hardware threads available for jobs
+	fn threads(&self) -> usize;
+
+	/// Memory in MiB available for jobs, if known
+	fn memory(&self) -> Option<u64>;
+
+	/// Most jobs the backend runs at a time, whatever their threads
+	fn max_slots(&self) -> Option<usize> {
+		None
+	}
+
+	/// Runs `command` with a POSIX shell on the node.
+	async fn execute(&self, command: &str) -> Result<JobOutput>;
+
+	/// Makes a copy of the local directory `workdir` available to jobs.
+	async fn prepare_workdir(&self, workdir: &str) -> Result<()>;
+
+	/// Runs `job` to completion in a fresh result directory.
+	async fn run_job(&self, job: &JobRun<'_>) -> Result<JobOutcome>;
+
+	/// Follows `job` if an earlier controller started it and it may still be
+	/// running, and returns how it ended. Backends that cannot follow jobs
+	/// kill whatever is left of it and return `None`, so that it is rerun.
+	async fn reattach(&self, job: &JobRun<'_>) -> Result<Option<JobOutcome>> {
+		self.kill_job(job).await?;
+		Ok(None)
+	}
+
+	/// Whether job `id`, which an earlier controller started and may have
+	/// left running, still runs. Backends that cannot follow jobs cannot tell.
+	async fn is_running(&self, _id: &str) -> Result<bool> {
+		anyhow::bail!("{} cannot tell whether jobs of an earlier run still run", self.name())
+	}
+
+	/// Kills `job` if it is still running, after [`run_job`](Backend::run_job)
+	/// was cancelled.
+	async fn kill_job(&self, job: &JobRun<'_>) -> Result<()>;
+
+	/// Moves the result directory of job `id` to the local `destination`.
+	async fn fetch_results(&self, id: &str, destination: &Path) -> Result<()>;
+
+	/// Removes everything the run left on the node.
+	async fn cleanup(&self) -> Result<()>;
+
+	/// Connects again after a command failed with [`Disconnected`]. Backends
+	/// that do not keep a connection have nothing to do.
+	async fn reconnect(&self) -> Result<()> {
+		Ok(())
+	}
+}
+
+/// Reconnects to `backend`, pausing longer before every attempt. Returns
+/// whether the connection is back.
+pub async fn reconnect(backend: &dyn Backend) -> bool {
+	let mut pause = RECONNECT_BACKOFF;
+	for attempt in 1..=RECONNECT_ATTEMPTS {
+		tokio::time::sleep(pause).await;
+		match backend.reconnect().await {
+			Ok(_) => return true,
+			Err(err) => warn!("Attempt {} of {} to reconnect to {} failed\n{:#}", attempt, RECONNECT_ATTEMPTS, backend.name(), err),
+		}
+		pause *= 2;
+	}
+	false
+}
+
+/// Runs `command` on `backend` like [`Backend::execute`], but reconnects and
+/// runs it again if the connection was lost. Only for commands that can run
+/// twice without harm.
+pub async fn execute_reconnecting(backend: &dyn Backend, command: &str) -> Result<JobOutput> {
+	loop {
+		match backend.execute(command).await {
+			Err(err) if err.is::<Disconnected>() => {
+				warn!("{:#}, reconnecting", err);
+				if !reconnect(backend).await {
+					return Err(err);
+				}
+			}
+			result => return result,
+		}
+	}
+}
+
+/// Shell command printing the memory statistics read by [`available_memory`]
+pub const MEMINFO_COMMAND: &str = "cat /proc/meminfo";
+
+/// `MemAvailable` of a `/proc/meminfo` in MiB. It counts memory that can be
+/// used without swapping, so memory that other processes hold is left out.
+pub fn available_memory(meminfo: &str) -> Option<u64> {
+	let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+	let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+	Some(kib / 1024)
+}
+
+/// Directory layout of a run on a node. `<root>/workdir` holds the copied
+/// workdir, `<root>/results/<id>` is the working directory of a job and
+/// `<root>/jobs/<id>` keeps its bookkeeping files.
+#[derive(Debug, Clone)]
+pub struct Scratch {
+	root: String,
+}
+
+impl Scratch {
+	pub fn new(name: &str) -> Self {
+		Self::with_root(format!("/tmp/MNER/{}", name))
+	}
+
+	pub fn with_root(root: String) -> Self {
+		Self { root }
+	}
+
+	/// The layout runs of `config` use: under `slurm.scratch_dir` for Slurm,
+	/// which has to be on a shared filesystem, and in `/tmp` otherwise.
+	pub fn for_config(config: &Config) -> Self {
+		match (config.backend, &config.slurm) {
+			(BackendKind::Slurm, Some(slurm)) => Self::with_root(format!("{}/{}", slurm.scratch_dir.trim_end_matches('/'), config.name)),
+			_ => Self::new(&config.name),
+		}
+	}
+
+	pub fn root(&self) -> &str {
+		&self.root
+	}
+
+	pub fn workdir(&self) -> String {
+		format!("{}/workdir", self.root)
+	}
+
+	pub fn result_dir(&self, id: &str) -> String {
+		format!("{}/results/{}", self.root, id)
+	}
+
+	/// Parent of the job directories
+	pub fn jobs_dir(&self) -> String {
+		format!("{}/jobs", self.root)
+	}
+
+	pub fn job_dir(&self, id: &str) -> String {
+		format!("{}/{}", self.jobs_dir(), id)
+	}
+
+	/// File holding the pid of the job's shell
+	pub fn pid_file(&self, id: &str) -> String {
+		format!("{}/pid", self.job_dir(id))
+	}
+
+	/// File holding the Unix time at which a detached job was started
+	pub fn started_file(&self, id: &str) -> String {
+		format!("{}/started", self.job_dir(id))
+	}
+
+	/// File holding the exit status of a detached job once it exited
+	pub fn exit_status_file(&self, id: &str) -> String {
+		format!("{}/exit_status", self.job_dir(id))
+	}
+
+	pub fn stdout_file(&self, id: &str) -> String {
+		format!("{}/stdout", self.job_dir(id))
+	}
+
+	pub fn stderr_file(&self, id: &str) -> String {
+		format!("{}/stderr", self.job_dir(id))
+	}
+
+	/// File holding the id of the job's container
+	pub fn cid_file(&self, id: &str) -> String {
+		format!("{}/cid", self.job_dir(id))
+	}
+
+	/// The shell words that start the executable of `job`. In a container
+	/// the workdir and the result directory are mounted at their paths on the
+	/// node, so the executable sees the same paths either way.
+	fn executable_command(&self, job: &JobRun<'_>) -> String {
+		let executable = format!("{}/{}", self.workdir(), job.executable);
+		let quote = |words: &[String]| words.iter().map(|word| shell_quote(word)).collect::<Vec<_>>().join(" ");
+		let Some(container) = job.container else {
+			return format!("{} {}", shell_quote(&executable), quote(job.argv));
+		};
+		let result_dir = self.result_dir(job.id);
+		let mut arguments = vec![
+			"--rm".to_string(),
+			"--init".to_string(),
+			format!("--cidfile={}", self.cid_file(job.id)),
+		];
+		if job.threads > 0 {
+			arguments.push(format!("--cpus={}", job.threads));
+		}
+		if let Some(memory) = job.memory {
+			arguments.push(format!("--memory={}m", memory));
+		}
+		arguments.extend([
+			format!("--volume={0}:{0}", self.workdir()),
+			format!("--volume={0}:{0}", result_dir),
+			format!("--workdir={}", result_dir),
+		]);
+		arguments.extend(container.mounts.iter().map(|mount| format!("--volume={}", mount)));
+		arguments.extend(container.run_args.iter().cloned());
+		arguments.push(container.image.clone());
+		arguments.push(executable);
+		arguments.extend(job.argv.iter().cloned());
+		// Docker runs containers as root unless told otherwise, which would
+		// leave results the user cannot move. Rootless podman maps root to the user.
+		let user = match container.runtime {
+			ContainerRuntime::Docker => " --user=\"$(id -u):$(id -g)\"",
+			ContainerRuntime::Podman => "",
+		};
+		format!("{} run{} {}", container.runtime.command(), user, quote(&arguments))
+	}
+
+	/// Shell command that runs `job` in its result directory. The shell has to
+	/// lead its own session or process group, so that the pid it records names
+	/// the processes to kill on timeout.
+	pub fn job_command(&self, job: &JobRun<'_>) -> String {
+		let result_dir = shell_quote(&self.result_dir(job.id));
+		let command = self.executable_command(job);
+		format!("rm -rf {result_dir} && mkdir -p {result_dir} {} && rm -f {} && cd {result_dir} && echo $$ > {} && exec {}",
+			shell_quote(&self.job_dir(job.id)), shell_quote(&self.cid_file(job.id)), shell_quote(&self.pid_file(job.id)), command)
+	}
+
+	/// Shell command that starts `job` in the background in a session of its
+	/// own and returns once the job recorded its pid. The session leader
+	/// writes the exit status when the job is done; as `$$` of a subshell is
+	/// that of its parent, the recorded pid names the session.
+	pub fn detached_command(&self, job: &JobRun<'_>) -> String {
+		let exit_status = shell_quote(&self.exit_status_file(job.id));
+		let pid_file = shell_quote(&self.pid_file(job.id));
+		let session = format!("( {} ) > {} 2> {}; echo $? > {exit_status}.tmp && mv {exit_status}.tmp {exit_status}",
+			self.job_command(job), shell_quote(&self.stdout_file(job.id)), shell_quote(&self.stderr_file(job.id)));
+		format!("mkdir -p {job_dir} && rm -f {pid_file} {exit_status} && date +%s > {} || exit 1; \
+			nohup setsid sh -c {} > /dev/null 2>&1 < /dev/null & \
+			while [ ! -s {pid_file} ] && [ ! -f {exit_status} ]; do sleep 0.1; done",
+			shell_quote(&self.started_file(job.id)), shell_quote(&session), job_dir = shell_quote(&self.job_dir(job.id)))
+	}
+
+	/// Shell command that prints `exited <status>` once the detached job
+	/// `id` exited, `running <seconds since its start>` while it runs and
+	/// `lost` if it is gone without an exit status.
+	pub fn detached_poll_command(&self, id: &str) -> String {
+		let exit_status = shell_quote(&self.exit_status_file(id));
+		let pid_file = shell_quote(&self.pid_file(id));
+		format!("if [ -f {exit_status} ]; then echo exited \"$(cat {exit_status})\"; \
+			elif [ -s {pid_file} ] && kill -0 \"$(cat {pid_file})\" 2>/dev/null; then echo running $(( $(date +%s) - $(cat {}) )); \
+			else echo lost; fi", shell_quote(&self.started_file(id)))
+	}
+
+	/// Shell command that kills the processes of `job`, first with SIGTERM
+	/// and, if any are left after [`KILL_GRACE_PERIOD`], with SIGKILL, and
+	/// its container if it has one.
+	pub fn kill_command(&self, job: &JobRun<'_>) -> String {
+		let pid_file = shell_quote(&self.pid_file(job.id));
+		let kill_container = match job.container {
+			Some(container) => {
+				let cid_file = shell_quote(&self.cid_file(job.id));
+				format!("[ -f {cid_file} ] && {} kill \"$(cat {cid_file})\" >/dev/null 2>&1; ", container.runtime.command())
+			}
+			None => String::new(),
+		};
+		let polls = KILL_GRACE_PERIOD.as_millis() / 100;
+		format!("if [ -f {pid_file} ]; then pid=$(cat {pid_file}); \
+			pkill -TERM -s $pid 2>/dev/null; kill -TERM -$pid 2>/dev/null; \
+			i=0; while [ $i -lt {polls} ] && {{ pgrep -s $pid >/dev/null 2>&1 || kill -0 -$pid 2>/dev/null; }}; do sleep 0.1; i=$((i + 1)); done; \
+			{kill_container}pkill -KILL -s $pid 2>/dev/null; kill -KILL -$pid 2>/dev/null; fi; true")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// Loses the connection for the first `drops` commands.
+	struct Flaky {
+		drops: usize,
+		executed: AtomicUsize,
+		reconnects: AtomicUsize,
+	}
+
+	#[async_trait]
+	impl Backend for Flaky {
+		fn name(&self) -> &str {
+			"flaky"
+		}
+
+		fn threads(&self) -> usize {
+			1
+		}
+
+		fn memory(&self) -> Option<u64> {
+			None
+		}
+
+		async fn execute(&self, _command: &str) -> Result<JobOutput> {
+			if self.executed.fetch_add(1, Ordering::SeqCst) < self.drops {
+				return Err(Disconnected { host: "flaky".to_string(), source: async_ssh2_tokio::Error::CommandDidntExit }.into());
+			}
+			Ok(JobOutput { exit_status: 0, stdout: "running 1".to_string(), stderr: String::new() })
+		}
+
+		async fn prepare_workdir(&self, _workdir: &str) -> Result<()> {
+			Ok(())
+		}
+
+		async fn run_job(&self, _job: &JobRun<'_>) -> Result<JobOutcome> {
+			anyhow::bail!("flaky runs no jobs")
+		}
+
+		async fn kill_job(&self, _job: &JobRun<'_>) -> Result<()> {
+			Ok(())
+		}
+
+		async fn fetch_results(&self, _id: &str, _destination: &Path) -> Result<()> {
+			Ok(())
+		}
+
+		async fn cleanup(&self) -> Result<()> {
+			Ok(())
+		}
+
+		async fn reconnect(&self) -> Result<()> {
+			self.reconnects.fetch_add(1, Ordering::SeqCst);
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn commands_run_again_after_reconnecting() {
+		let backend = Flaky { drops: 1, executed: AtomicUsize::new(0), reconnects: AtomicUsize::new(0) };
+		let output = execute_reconnecting(&backend, "poll").await.unwrap();
+		assert_eq!(output.stdout, "running 1");
+		assert_eq!(backend.executed.load(Ordering::SeqCst), 2);
+		assert_eq!(backend.reconnects.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn disconnects_are_recognized_through_context() {
+		let err: anyhow::Error = Disconnected { host: "flaky".to_string(), source: async_ssh2_tokio::Error::CommandDidntExit }.into();
+		assert!(err.context("Failed to execute kill").is::<Disconnected>());
+		assert!(!anyhow::anyhow!("kill failed").is::<Disconnected>());
+	}
+}
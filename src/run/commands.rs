@@ -1,21 +1,217 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
 use tokio::process::Command;
 
-pub async fn rsync(from: &str, to: &str, delete_src: bool) -> anyhow::Result<()> {
+/// Quotes `s` as a single word for a POSIX shell.
+pub fn shell_quote(s: &str) -> String {
+	let is_safe = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '/' | '=' | ':' | ',' | '+' | '@' | '%' | '-');
+	if !s.is_empty() && s.chars().all(is_safe) {
+		s.to_string()
+	} else {
+		format!("'{}'", s.replace('\'', "'\\''"))
+	}
+}
+
+/// Environment variable that makes this program act as an `SSH_ASKPASS` helper
+/// printing the contents of the file it names. The secret itself stays out of
+/// the environment, which every descendant inherits and /proc exposes.
+pub const ASKPASS_FILE_VAR: &str = "MNER_ASKPASS_FILE";
+
+/// The ssh command rsync connects with.
+pub struct RemoteShell {
+	pub command: String,
+	/// Passphrase or password ssh reads through `SSH_ASKPASS`
+	pub askpass_secret: Option<String>,
+}
+
+/// Runs rsync, connecting through `rsh` if given.
+pub async fn rsync(from: &str, to: &str, delete_src: bool, rsh: Option<&RemoteShell>) -> anyhow::Result<()> {
 	/*let from = if from.ends_with('/') && !fs::metadata(from).map(|m| m.is_dir()).unwrap_or(false){
 		from.to_string()
 	}else {
 		format!("{}/", from)
 	};*/
 	let mut output_cmd = Command::new("rsync");
-	output_cmd.arg("-arz").arg("--delete").arg("--mkpath");
+	output_cmd.arg("-arz").arg("--delete").arg("--mkpath").arg("--protect-args");
 	if delete_src {
 		output_cmd.arg("--remove-source-files");
 	}
+	// Only readable by us and deleted once rsync is done
+	let mut secret_file = None;
+	if let Some(rsh) = rsh {
+		output_cmd.arg("-e").arg(&rsh.command);
+		if let Some(secret) = &rsh.askpass_secret {
+			let mut file = tempfile::Builder::new().prefix("mner-askpass").tempfile()?;
+			file.write_all(secret.as_bytes())?;
+			file.flush()?;
+			output_cmd.env("SSH_ASKPASS", std::env::current_exe()?)
+				.env("SSH_ASKPASS_REQUIRE", "force")
+				.env(ASKPASS_FILE_VAR, file.path());
+			secret_file = Some(file);
+		}
+	}
 	output_cmd.arg(from).arg(to);
 	let output = output_cmd.output().await?;
+	drop(secret_file);
 	if !output.status.success() {
 		let stderr = String::from_utf8_lossy(&output.stderr);
 		return Err(anyhow::anyhow!("rsync failed: {}", stderr));
 	}
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Makes `to` a copy of the directory `from`, like rsync with `--delete`
+/// does with `from/`. Like rsync, `to` is updated in place and every file
+/// is replaced by renaming a fresh copy over it, so jobs still running from
+/// `to` keep the files they opened. With `delete_src` the copy becomes a move.
+pub async fn copy_dir(from: &str, to: &str, delete_src: bool) -> anyhow::Result<()> {
+	let (from, to) = (from.to_string(), to.to_string());
+	tokio::task::spawn_blocking(move || -> io::Result<()> {
+		let (from, to) = (Path::new(&from), Path::new(&to));
+		if let Some(parent) = to.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		if delete_src && !to.exists() && fs::rename(from, to).is_ok() {
+			return Ok(());
+		}
+		sync_dir(from, to)?;
+		if delete_src {
+			fs::remove_dir_all(from)?;
+		}
+		Ok(())
+	}).await?.map_err(|err| anyhow::anyhow!("copy failed: {}", err))
+}
+
+fn sync_dir(from: &Path, to: &Path) -> io::Result<()> {
+	if fs::symlink_metadata(to).is_ok_and(|metadata| !metadata.is_dir()) {
+		fs::remove_file(to)?;
+	}
+	fs::create_dir_all(to)?;
+	let mut names = HashSet::new();
+	for entry in fs::read_dir(from)? {
+		let entry = entry?;
+		let target = to.join(entry.file_name());
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			sync_dir(&entry.path(), &target)?;
+		} else {
+			let mut partial_name = entry.file_name();
+			partial_name.push(".partial");
+			let partial = to.join(partial_name);
+			let _ = fs::remove_file(&partial);
+			if file_type.is_symlink() {
+				std::os::unix::fs::symlink(fs::read_link(entry.path())?, &partial)?;
+			} else {
+				fs::copy(entry.path(), &partial)?;
+			}
+			if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir()) {
+				fs::remove_dir_all(&target)?;
+			}
+			fs::rename(&partial, &target)?;
+		}
+		names.insert(entry.file_name());
+	}
+	for entry in fs::read_dir(to)? {
+		let entry = entry?;
+		if names.contains(&entry.file_name()) {
+			continue;
+		}
+		if entry.file_type()?.is_dir() {
+			fs::remove_dir_all(entry.path())?;
+		} else {
+			fs::remove_file(entry.path())?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::MetadataExt;
+
+	/// What `sh` makes of the quoted `s`.
+	fn through_shell(s: &str) -> String {
+		let output = std::process::Command::new("sh")
+			.arg("-c")
+			.arg(format!("printf %s {}", shell_quote(s)))
+			.output()
+			.unwrap();
+		String::from_utf8(output.stdout).unwrap()
+	}
+
+	#[test]
+	fn safe_words_stay_unquoted() {
+		assert_eq!(shell_quote("--threads=4"), "--threads=4");
+		assert_eq!(shell_quote("/tmp/a.b"), "/tmp/a.b");
+	}
+
+	#[test]
+	fn empty_string_is_one_word() {
+		assert_eq!(shell_quote(""), "''");
+		assert_eq!(through_shell(""), "");
+	}
+
+	#[test]
+	fn special_characters_survive_the_shell() {
+		for s in ["it's", "'", "''", "$(touch /tmp/mner-quote)", "`id`", "a\nb", "$HOME", "a b\tc", "*", "\\", "\"", "é ü"] {
+			assert_eq!(through_shell(s), s, "quoted as {}", shell_quote(s));
+		}
+	}
+
+	fn write(path: &Path, content: &str) {
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(path, content).unwrap();
+	}
+
+	fn path_str(path: &Path) -> &str {
+		path.to_str().unwrap()
+	}
+
+	#[tokio::test]
+	async fn copies_update_the_target_in_place() {
+		let dir = tempfile::tempdir().unwrap();
+		let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+		write(&from.join("run.sh"), "new");
+		write(&from.join("data/input"), "input");
+		std::os::unix::fs::symlink("run.sh", from.join("link")).unwrap();
+		write(&to.join("run.sh"), "old");
+		write(&to.join("stale"), "stale");
+		write(&to.join("data/stale/file"), "stale");
+		write(&to.join("link/file"), "was a directory");
+		let inode = fs::metadata(&to).unwrap().ino();
+		let open_file = fs::File::open(to.join("run.sh")).unwrap();
+
+		copy_dir(path_str(&from), path_str(&to), false).await.unwrap();
+
+		assert_eq!(fs::metadata(&to).unwrap().ino(), inode);
+		assert_eq!(fs::read_to_string(to.join("run.sh")).unwrap(), "new");
+		assert_eq!(fs::read_to_string(to.join("data/input")).unwrap(), "input");
+		assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("run.sh"));
+		assert!(!to.join("stale").exists());
+		assert!(!to.join("data/stale").exists());
+		// The old file lives on for whoever has it open
+		assert_eq!(io::read_to_string(open_file).unwrap(), "old");
+		assert_eq!(fs::read_dir(&to).unwrap().count(), 3);
+		assert!(from.join("run.sh").exists());
+	}
+
+	#[tokio::test]
+	async fn moves_remove_the_source() {
+		let dir = tempfile::tempdir().unwrap();
+		let (from, to) = (dir.path().join("from"), dir.path().join("results/to"));
+		write(&from.join("out.txt"), "first");
+		copy_dir(path_str(&from), path_str(&to), true).await.unwrap();
+		assert!(!from.exists());
+		assert_eq!(fs::read_to_string(to.join("out.txt")).unwrap(), "first");
+
+		write(&from.join("other.txt"), "second");
+		copy_dir(path_str(&from), path_str(&to), true).await.unwrap();
+		assert!(!from.exists());
+		assert!(!to.join("out.txt").exists());
+		assert_eq!(fs::read_to_string(to.join("other.txt")).unwrap(), "second");
+	}
+}
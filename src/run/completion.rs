@@ -0,0 +1,242 @@
+//! Completion markers of job result folders.
+//!
+//! Every job gets a folder `<output>/<name>/<permutation id>/`. Once the job
+//! has finished and its files (results rsynced from the node, `stdout` and
+//! `stderr`) are in place, a file named [`COMPLETE_MARKER`] is written into
+//! that folder. It holds a single line with the outcome of the job:
+//! `succeeded`, `failed` or `timed_out`. The marker is written to a temporary
+//! file and renamed, so it either exists with its full content or not at all.
+//!
+//! A folder without a marker belongs to a job that was interrupted while its
+//! results were being copied and is treated as never having run.
+//!
+//! Earlier versions marked a folder with an empty file named `succeeded` or
+//! `failed` instead. Such folders count as finished and get a marker the next
+//! time they are looked at by `Run`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use crate::run::config_file::Permutation;
+use clap::ValueEnum;
+use spdlog::prelude::*;
+
+pub const COMPLETE_MARKER: &str = "complete";
+const COMPLETE_MARKER_TMP: &str = ".complete.tmp";
+/// Markers of earlier versions, an empty file per folder
+const LEGACY_SUCCEEDED_MARKER: &str = "succeeded";
+const LEGACY_FAILED_MARKER: &str = "failed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+	Succeeded,
+	Failed,
+	TimedOut,
+	Incomplete,
+}
+
+impl JobStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			JobStatus::Succeeded => "succeeded",
+			JobStatus::Failed => "failed",
+			JobStatus::TimedOut => "timed_out",
+			JobStatus::Incomplete => "incomplete",
+		}
+	}
+
+	fn parse(s: &str) -> Option<Self> {
+		match s.trim() {
+			"succeeded" => Some(JobStatus::Succeeded),
+			"failed" => Some(JobStatus::Failed),
+			"timed_out" => Some(JobStatus::TimedOut),
+			_ => None,
+		}
+	}
+
+	/// Reads the completion marker of a job result folder, falling back to
+	/// the markers of earlier versions.
+	pub fn from_result_dir(dir: &Path) -> Self {
+		match fs::read_to_string(dir.join(COMPLETE_MARKER)) {
+			Ok(content) => Self::parse(&content).unwrap_or(JobStatus::Incomplete),
+			Err(_) => Self::from_legacy_marker(dir).unwrap_or(JobStatus::Incomplete),
+		}
+	}
+
+	fn from_legacy_marker(dir: &Path) -> Option<Self> {
+		if dir.join(LEGACY_SUCCEEDED_MARKER).is_file() {
+			Some(JobStatus::Succeeded)
+		} else if dir.join(LEGACY_FAILED_MARKER).is_file() {
+			Some(JobStatus::Failed)
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Display for JobStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// Atomically marks the job result folder `dir` as complete with `status`.
+pub fn mark_complete(dir: &Path, status: JobStatus) -> std::io::Result<()> {
+	let tmp_path = dir.join(COMPLETE_MARKER_TMP);
+	let mut file = File::create(&tmp_path)?;
+	writeln!(file, "{}", status)?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, dir.join(COMPLETE_MARKER))
+}
+
+/// Which finished jobs a rerun leaves alone.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipPolicy {
+	/// Skip jobs that succeeded, rerun failed and timed out ones
+	Succeeded,
+	/// Skip jobs that failed or timed out, rerun succeeded ones
+	Failed,
+	/// Skip every finished job
+	All,
+	/// Rerun every job
+	None,
+}
+
+impl SkipPolicy {
+	pub fn skips(&self, status: JobStatus) -> bool {
+		match (self, status) {
+			(_, JobStatus::Incomplete) => false,
+			(SkipPolicy::All, _) => true,
+			(SkipPolicy::None, _) => false,
+			(SkipPolicy::Succeeded, status) => status == JobStatus::Succeeded,
+			(SkipPolicy::Failed, status) => status == JobStatus::Failed || status == JobStatus::TimedOut,
+		}
+	}
+}
+
+/// Removes from `permutations` the jobs that already have a result folder
+/// that `policy` skips. Folders with only a legacy marker get a completion
+/// marker. Result folders without any marker are left over from an
+/// interrupted run; they are deleted so the job starts clean.
+pub fn filter_finished(results_path: &Path, permutations: &mut HashMap<String, Permutation>, policy: SkipPolicy) -> std::io::Result<()> {
+	for entry in fs::read_dir(results_path)? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() {
+			continue;
+		}
+		let folder_name = entry.file_name();
+		let Some(folder_name_str) = folder_name.to_str() else {
+			continue;
+		};
+		if !permutations.contains_key(folder_name_str) {
+			continue;
+		}
+		if !entry.path().join(COMPLETE_MARKER).exists()
+			&& let Some(status) = JobStatus::from_legacy_marker(&entry.path()) {
+			info!("Migrating the {} marker of {}", status, folder_name_str);
+			if let Err(err) = mark_complete(&entry.path(), status) {
+				error!("Failed to write completion marker for {}\n{}", folder_name_str, err);
+			}
+		}
+		let status = JobStatus::from_result_dir(&entry.path());
+		if status == JobStatus::Incomplete {
+			warn!("Result folder of {} has no completion marker, it will be rerun", folder_name_str);
+			if let Err(err) = fs::remove_dir_all(entry.path()) {
+				error!("Failed to remove incomplete result folder {}\n{}", entry.path().display(), err);
+			}
+		} else if policy.skips(status) {
+			debug!("Skipping {} job {}", status, folder_name_str);
+			permutations.remove(folder_name_str);
+		}
+	}
+	Ok(())
+}
+
+/// The jobs of `permutations` that `policy` skips, sorted by id, with their
+/// status. Unlike [`filter_finished`] this leaves the results alone.
+pub fn skipped(results_path: &Path, permutations: &HashMap<String, Permutation>, policy: SkipPolicy) -> Vec<(String, JobStatus)> {
+	let mut skipped: Vec<(String, JobStatus)> = permutations.keys()
+		.map(|id| (id.clone(), JobStatus::from_result_dir(&results_path.join(id))))
+		.filter(|(_, status)| policy.skips(*status))
+		.collect();
+	skipped.sort_by(|a, b| a.0.cmp(&b.0));
+	skipped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn permutations(ids: &[&str]) -> HashMap<String, Permutation> {
+		ids.iter()
+			.map(|id| (id.to_string(), Permutation { id: id.to_string(), argv: Vec::new(), arguments: Vec::new(), repeat: 0 }))
+			.collect()
+	}
+
+	/// Result folders for `succeeded`, `failed`, `timed_out` and `incomplete`
+	/// jobs, the last without a marker.
+	fn result_folders() -> tempfile::TempDir {
+		let dir = tempfile::tempdir().unwrap();
+		for (id, status) in [("succeeded", JobStatus::Succeeded), ("failed", JobStatus::Failed), ("timed_out", JobStatus::TimedOut)] {
+			fs::create_dir(dir.path().join(id)).unwrap();
+			mark_complete(&dir.path().join(id), status).unwrap();
+		}
+		fs::create_dir(dir.path().join("incomplete")).unwrap();
+		fs::write(dir.path().join("incomplete/stdout"), "partial").unwrap();
+		dir
+	}
+
+	fn remaining(policy: SkipPolicy) -> Vec<String> {
+		let results = result_folders();
+		let mut permutations = permutations(&["succeeded", "failed", "timed_out", "incomplete", "new"]);
+		filter_finished(results.path(), &mut permutations, policy).unwrap();
+		let mut ids: Vec<String> = permutations.into_keys().collect();
+		ids.sort();
+		ids
+	}
+
+	#[test]
+	fn finished_jobs_are_skipped_by_policy() {
+		assert_eq!(remaining(SkipPolicy::Succeeded), ["failed", "incomplete", "new", "timed_out"]);
+		assert_eq!(remaining(SkipPolicy::Failed), ["incomplete", "new", "succeeded"]);
+		assert_eq!(remaining(SkipPolicy::All), ["incomplete", "new"]);
+		assert_eq!(remaining(SkipPolicy::None), ["failed", "incomplete", "new", "succeeded", "timed_out"]);
+	}
+
+	#[test]
+	fn folders_without_a_marker_are_removed() {
+		let results = result_folders();
+		filter_finished(results.path(), &mut permutations(&["succeeded", "incomplete"]), SkipPolicy::All).unwrap();
+		assert!(!results.path().join("incomplete").exists());
+		assert!(results.path().join("succeeded").exists());
+		// Folders of jobs that are not in the experiment are left alone
+		let results = result_folders();
+		filter_finished(results.path(), &mut permutations(&["succeeded"]), SkipPolicy::All).unwrap();
+		assert!(results.path().join("incomplete/stdout").exists());
+	}
+
+	#[test]
+	fn legacy_markers_are_migrated() {
+		let dir = tempfile::tempdir().unwrap();
+		for (id, marker) in [("old_ok", LEGACY_SUCCEEDED_MARKER), ("old_failed", LEGACY_FAILED_MARKER)] {
+			fs::create_dir(dir.path().join(id)).unwrap();
+			File::create(dir.path().join(id).join(marker)).unwrap();
+		}
+		let mut permutations = permutations(&["old_ok", "old_failed"]);
+		filter_finished(dir.path(), &mut permutations, SkipPolicy::Succeeded).unwrap();
+		assert_eq!(fs::read_to_string(dir.path().join("old_ok").join(COMPLETE_MARKER)).unwrap(), "succeeded\n");
+		assert_eq!(fs::read_to_string(dir.path().join("old_failed").join(COMPLETE_MARKER)).unwrap(), "failed\n");
+		assert!(!permutations.contains_key("old_ok"));
+		assert!(permutations.contains_key("old_failed"));
+	}
+
+	#[test]
+	fn incomplete_jobs_are_never_skipped() {
+		for policy in [SkipPolicy::Succeeded, SkipPolicy::Failed, SkipPolicy::All, SkipPolicy::None] {
+			assert!(!policy.skips(JobStatus::Incomplete));
+		}
+	}
+}
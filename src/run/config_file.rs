@@ -1,81 +1,883 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use serde::Deserialize;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use evalexpr::ContextWithMutableVariables;
+use serde::{Deserialize, Deserializer};
+use spdlog::prelude::*;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub name: String,
+    /// Where jobs run
+    #[serde(default)]
+    pub backend: Backend,
+    /// Hosts of the SSH backend
+    #[serde(default)]
     pub hosts: Vec<String>,
+    /// Settings of the Slurm backend
+    pub slurm: Option<SlurmConfig>,
     pub workdir: String,
     pub executable: String,
     pub repeat: usize,
-    pub threads_per_task: usize,
+    /// Threads of a job, a number or an expression over the arguments. A job
+    /// with 0 runs alone on its node and may use all of the node's threads.
+    pub threads_per_task: PerTask,
+    /// Memory of a job in MiB, a number or an expression over the arguments.
+    /// Jobs only start on nodes with that much memory left.
+    pub memory_per_task: Option<PerTask>,
+    /// Limits of specific hosts, keyed by host name as in `hosts`
+    #[serde(default)]
+    pub host_settings: HashMap<String, HostSettings>,
+    /// The run is aborted if fewer hosts than this can be reached
+    #[serde(default = "default_min_nodes")]
+    pub min_nodes: usize,
+    /// Time limit of a single job in seconds
+    pub timeout: Option<u64>,
+    /// Timeouts for specific argument combinations; the last match wins
+    #[serde(default)]
+    pub timeout_overrides: Vec<TimeoutOverride>,
+    /// How often a job is retried after an SSH or rsync error
+    #[serde(default)]
+    pub retries: usize,
+    /// Also retry jobs whose command exited with a non-zero status
+    #[serde(default)]
+    pub retry_failed_jobs: bool,
+    /// Delay in seconds before the first retry, doubled for every further one
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
+    #[serde(default)]
+    pub argument_style: ArgumentStyle,
+    /// Start jobs in sessions of their own that outlive the SSH connection,
+    /// so that they keep running when the controller goes away
+    #[serde(default)]
+    pub detached: bool,
+    /// Run the executable in a container instead of directly on the node
+    pub container: Option<ContainerConfig>,
+    /// How to log in to specific hosts, keyed by host name as in `hosts`
+    #[serde(default)]
+    pub auth: HashMap<String, HostAuth>,
+    /// Groups of arguments whose values are paired index by index instead
+    /// of crossed, e.g. `[["dataset", "input_size"]]`. The groups are crossed
+    /// with each other and with the remaining arguments.
+    #[serde(default)]
+    pub zip: Vec<Vec<String>>,
+    /// Expressions over the arguments of a job, such as
+    /// `hash_size == 32 && percentage_of_duplicates > 0.5`; jobs for which
+    /// any of them is true are left out of the grid
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Extra jobs added after `exclude`, each assigning every argument
+    #[serde(default, deserialize_with = "deserialize_points")]
+    pub include: Vec<HashMap<String, String>>,
+    #[serde(deserialize_with = "deserialize_arguments")]
     pub arguments: HashMap<String, Vec<String>>,
+    /// The jobs, worked out once, see [`get_arguments_permutations`](Config::get_arguments_permutations)
+    #[serde(skip)]
+    permutations: OnceLock<HashMap<String, Permutation>>,
+}
+
+fn default_min_nodes() -> usize {
+    1
+}
+
+fn default_retry_backoff() -> u64 {
+    10
 }
 
 #[derive(Debug, Clone)]
 pub struct Permutation {
+    /// Identifier of the permutation, safe to use as a directory name
     pub id: String,
-    pub parameters: String
+    /// Arguments passed to the executable, one entry per argv element
+    pub argv: Vec<String>,
+    /// Argument names and values of this permutation, sorted by name
+    pub arguments: Vec<(String, String)>,
+    pub repeat: usize,
+}
+
+/// How an argument is turned into argv elements of the executable.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgumentStyle {
+    /// A single element `--name=value`
+    #[default]
+    Joined,
+    /// Two elements `--name` and `value`
+    Separate,
+}
+
+impl Permutation {
+    /// Variables for evalexpr expressions, one per argument. Values that
+    /// look like numbers or booleans are typed accordingly.
+    pub fn expression_context(&self) -> evalexpr::HashMapContext {
+        let mut context = evalexpr::HashMapContext::new();
+        for (name, value) in &self.arguments {
+            let value = if let Ok(int) = value.parse::<i64>() {
+                evalexpr::Value::Int(int)
+            } else if let Ok(float) = value.parse::<f64>() {
+                evalexpr::Value::Float(float)
+            } else if let Ok(boolean) = value.parse::<bool>() {
+                evalexpr::Value::Boolean(boolean)
+            } else {
+                evalexpr::Value::String(value.clone())
+            };
+            // Only fails for a type change of an existing variable
+            let _ = context.set_value(name.clone(), value);
+        }
+        context
+    }
+
+    /// Whether every `name = value` pair of `selector` is part of this permutation.
+    pub fn matches(&self, selector: &HashMap<String, String>) -> bool {
+        selector.iter().all(|(name, value)| {
+            self.arguments.iter().any(|(n, v)| n == name && v == value)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TimeoutOverride {
+    /// Argument values a permutation must have for the override to apply
+    #[serde(deserialize_with = "deserialize_selector")]
+    pub arguments: HashMap<String, String>,
+    /// Timeout in seconds
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// On the hosts in `hosts`, over SSH
+    #[default]
+    Ssh,
+    /// As child processes on this machine, ignoring `hosts`
+    Local,
+    /// As Slurm array jobs submitted from a login node
+    Slurm,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlurmConfig {
+    /// Host to run sbatch on over SSH; it runs on this machine if omitted
+    pub login_host: Option<String>,
+    /// Absolute path of a directory that the login and compute nodes share
+    pub scratch_dir: String,
+    pub partition: Option<String>,
+    /// Further arguments passed to sbatch, e.g. `--account=...`
+    #[serde(default)]
+    pub sbatch_args: Vec<String>,
+    /// How many jobs may be queued or running at the same time
+    #[serde(default = "default_slurm_max_jobs")]
+    pub max_jobs: usize,
+    /// Seconds between two polls of squeue
+    #[serde(default = "default_slurm_poll_interval")]
+    pub poll_interval: u64,
+}
+
+fn default_slurm_max_jobs() -> usize {
+    100
+}
+
+fn default_slurm_poll_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRuntime {
+    #[default]
+    Podman,
+    Docker,
+}
+
+impl ContainerRuntime {
+    pub fn command(&self) -> &'static str {
+        match self {
+            ContainerRuntime::Podman => "podman",
+            ContainerRuntime::Docker => "docker",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContainerConfig {
+    #[serde(default)]
+    pub runtime: ContainerRuntime,
+    pub image: String,
+    /// Further bind mounts as `host_path:container_path[:options]`
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    /// Further arguments of `run`, e.g. `--network=none`
+    #[serde(default)]
+    pub run_args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PerTask {
+    Fixed(u64),
+    /// An evalexpr expression in which the arguments of a job are variables,
+    /// e.g. `if(hash_size > 1000000, 4, 1)`
+    Expression(String),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HostSettings {
+    /// Threads to use instead of what `nproc` reports
+    pub threads: Option<usize>,
+    /// Threads left free for other users of the host
+    #[serde(default)]
+    pub reserved_cores: usize,
+    /// Most jobs run at the same time
+    pub max_slots: Option<usize>,
+    /// Memory in MiB to use instead of what `/proc/meminfo` reports
+    pub memory: Option<u64>,
+    /// Memory in MiB left free for other users of the host
+    #[serde(default)]
+    pub reserved_memory: u64,
+}
+
+impl PerTask {
+    /// The amount for `permutation`, with fractions rounded up. `setting`
+    /// names the config key in errors.
+    pub fn amount_for(&self, permutation: &Permutation, setting: &str) -> Result<u64> {
+        let expression = match self {
+            PerTask::Fixed(amount) => return Ok(*amount),
+            PerTask::Expression(expression) => expression,
+        };
+        let value = evalexpr::eval_with_context(expression, &permutation.expression_context())
+            .with_context(|| format!("failed to evaluate {} for {}", setting, permutation.id))?;
+        let amount = match value {
+            evalexpr::Value::Int(amount) => amount as f64,
+            evalexpr::Value::Float(amount) => amount.ceil(),
+            other => anyhow::bail!("{} of {} is {}, expected a number", setting, permutation.id, other),
+        };
+        if amount < 0.0 {
+            anyhow::bail!("{} of {} is negative: {}", setting, permutation.id, amount);
+        }
+        Ok(amount as u64)
+    }
+}
+
+/// How much of a node jobs may use.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeCapacity {
+    /// Threads shared by the running jobs
+    pub threads: usize,
+    /// Memory in MiB shared by the running jobs, unlimited if unknown
+    pub memory: Option<u64>,
+    /// Most jobs running at the same time
+    pub slots: usize,
+}
+
+/// A way of logging in to a host.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthKind {
+    /// Private key files, asking for the passphrase of encrypted ones
+    Key,
+    /// A running ssh-agent found through `SSH_AUTH_SOCK`
+    Agent,
+    /// A password that is asked for once per host
+    Password,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HostAuth {
+    /// Methods tried in this order
+    #[serde(default = "default_auth_methods")]
+    pub methods: Vec<AuthKind>,
+    /// Private keys tried before the ones from the command line and ssh_config
+    #[serde(default)]
+    pub identity_files: Vec<String>,
+}
+
+pub fn default_auth_methods() -> Vec<AuthKind> {
+    vec![AuthKind::Key, AuthKind::Agent]
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, falling back to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+/// Argument values may be written as strings, numbers or booleans; they are
+/// all passed to the executable as text.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ArgumentValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ArgumentValue {
+    fn into_string(self) -> String {
+        match self {
+            ArgumentValue::String(s) => s,
+            ArgumentValue::Int(i) => i.to_string(),
+            // Debug keeps the decimal point and round-trips, so 1.0 stays
+            // distinct from 1 and no precision is lost
+            ArgumentValue::Float(f) => format!("{f:?}"),
+            ArgumentValue::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+fn deserialize_arguments<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let arguments = HashMap::<String, Vec<ArgumentValue>>::deserialize(deserializer)?;
+    Ok(arguments.into_iter()
+        .map(|(key, values)| (key, values.into_iter().map(ArgumentValue::into_string).collect()))
+        .collect())
+}
+
+fn deserialize_points<'de, D>(deserializer: D) -> Result<Vec<HashMap<String, String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let points = Vec::<HashMap<String, ArgumentValue>>::deserialize(deserializer)?;
+    Ok(points.into_iter()
+        .map(|point| point.into_iter().map(|(key, value)| (key, value.into_string())).collect())
+        .collect())
+}
+
+fn deserialize_selector<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let selector = HashMap::<String, ArgumentValue>::deserialize(deserializer)?;
+    Ok(selector.into_iter()
+        .map(|(key, value)| (key, value.into_string()))
+        .collect())
 }
 
 impl Config {
-    pub fn new(name: &str) -> Config {
-        let s = fs::read_to_string(name).expect("failed to read config file");
+    /// Loads a config file. The format is taken from `format` if given,
+    /// otherwise from the file extension.
+    pub fn new(path: &str, format: Option<ConfigFormat>) -> Result<Config> {
+        let s = fs::read_to_string(path).with_context(|| format!("failed to read config file {}", path))?;
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(Path::new(path)));
 
-        toml::from_str(&s).expect("failed to parse TOML into Config")
+        let config: Config = match format {
+            ConfigFormat::Toml => toml::from_str(&s)
+                .with_context(|| format!("failed to parse TOML config {}", path))?,
+            ConfigFormat::Yaml => serde_yaml_ng::from_str(&s)
+                .with_context(|| format!("failed to parse YAML config {}", path))?,
+        };
+        config.validate().with_context(|| format!("invalid config {}", path))?;
+        Ok(config)
     }
-    pub fn get_arguments_permutations(&self) -> HashMap<String, String> {
+
+    /// Checks what the format alone cannot express.
+    fn validate(&self) -> Result<()> {
+        if let PerTask::Fixed(0) = self.threads_per_task {
+            warn!("threads_per_task = 0 is deprecated; to run one job per node, set host_settings.<host>.max_slots = 1");
+        }
+        let mut zipped = HashSet::new();
+        for group in &self.zip {
+            let mut lengths = Vec::with_capacity(group.len());
+            for name in group {
+                let values = self.arguments.get(name)
+                    .with_context(|| format!("zip names {}, which is not an argument", name))?;
+                if !zipped.insert(name) {
+                    anyhow::bail!("argument {} is in more than one zip group", name);
+                }
+                lengths.push((name.as_str(), values.len()));
+            }
+            // Empty groups zip nothing and are left out of the grid
+            let Some(&(_, first_length)) = lengths.first() else {
+                continue;
+            };
+            if lengths.iter().any(|(_, length)| *length != first_length) {
+                let lengths: Vec<String> = lengths.iter().map(|(name, length)| format!("{} has {}", name, length)).collect();
+                anyhow::bail!("zipped arguments need the same number of values: {}", lengths.join(", "));
+            }
+            let mut seen = HashSet::new();
+            for index in 0..first_length {
+                let row: Vec<&String> = group.iter().map(|name| &self.arguments[name][index]).collect();
+                if !seen.insert(row.clone()) {
+                    anyhow::bail!("zip group {} has the values {:?} more than once", group.join(", "), row);
+                }
+            }
+        }
+        for (name, values) in &self.arguments {
+            if zipped.contains(name) {
+                continue;
+            }
+            let mut seen = HashSet::new();
+            if let Some(duplicate) = values.iter().find(|value| !seen.insert(*value)) {
+                anyhow::bail!("argument {} has the value {} more than once", name, duplicate);
+            }
+        }
+
+        for point in &self.include {
+            let mut names: Vec<String> = point.keys().cloned().collect();
+            names.sort();
+            if names != self.argument_names() {
+                anyhow::bail!("include point {:?} has to assign exactly the arguments {}", point, self.argument_names().join(", "));
+            }
+        }
+        // Sanitized ids are hashed, so distinct arguments could still share one
+        let grid = self.grid();
+        let combinations: usize = self.axes().iter().map(|axis| axis.len()).product();
+        if !self.arguments.is_empty() && grid.len() != combinations * self.repeat {
+            anyhow::bail!("two argument combinations map to the same job id");
+        }
+        let mut ids: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        let points = self.include.iter().map(|point| {
+            let mut arguments: Vec<(String, String)> = point.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            arguments.sort();
+            arguments
+        });
+        for arguments in grid.values().filter(|p| p.repeat == 0).map(|p| p.arguments.clone()).chain(points) {
+            let id = permutation_id(&arguments);
+            if let Some(other) = ids.get(&id) && *other != arguments {
+                anyhow::bail!("the arguments {:?} and {:?} both map to the job id {}", other, arguments, id);
+            }
+            ids.insert(id, arguments);
+        }
+        // Expressions naming unknown arguments only fail once evaluated
+        let mut permutations = HashMap::with_capacity(grid.len());
+        for (id, permutation) in grid {
+            if !self.is_excluded(&permutation)? {
+                permutations.insert(id, permutation);
+            }
+        }
+        self.insert_included(&mut permutations);
+        let _ = self.permutations.set(permutations);
+        Ok(())
+    }
+
+    /// Whether one of the `exclude` expressions is true for `permutation`.
+    fn is_excluded(&self, permutation: &Permutation) -> Result<bool> {
+        if self.exclude.is_empty() {
+            return Ok(false);
+        }
+        let context = permutation.expression_context();
+        for expression in &self.exclude {
+            let excluded = evalexpr::eval_boolean_with_context(expression, &context)
+                .with_context(|| format!("failed to evaluate exclude expression `{}` for {}", expression, permutation.id))?;
+            if excluded {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The axes of the parameter grid, each a list of assignments to one
+    /// argument or to a zip group, ordered by their first argument name.
+    fn axes(&self) -> Vec<Vec<Vec<(String, String)>>> {
+        let zipped: HashSet<&String> = self.zip.iter().flatten().collect();
+        let mut axes: Vec<Vec<Vec<(String, String)>>> = self.zip.iter()
+            .filter(|group| !group.is_empty())
+            .map(|group| {
+                let mut group = group.clone();
+                group.sort();
+                (0..self.arguments[&group[0]].len())
+                    .map(|index| group.iter().map(|name| (name.clone(), self.arguments[name][index].clone())).collect())
+                    .collect()
+            })
+            .collect();
+        axes.extend(self.arguments.iter()
+            .filter(|(name, _)| !zipped.contains(name))
+            .map(|(name, values)| values.iter().map(|value| vec![(name.clone(), value.clone())]).collect()));
+        axes.sort_by(|a, b| a.first().map(|assignment| &assignment[0].0).cmp(&b.first().map(|assignment| &assignment[0].0)));
+        axes
+    }
+    /// The jobs of the experiment: the grid of `arguments` and `zip`, less
+    /// what `exclude` matches, plus the `include` points. Loading the config
+    /// works them out, so the expressions are not evaluated again.
+    pub fn get_arguments_permutations(&self) -> HashMap<String, Permutation> {
+        self.permutations.get_or_init(|| {
+            let mut permutations = self.grid();
+            // Evaluation errors were ruled out when the config was loaded
+            permutations.retain(|_, permutation| !self.is_excluded(permutation).unwrap_or(false));
+            self.insert_included(&mut permutations);
+            permutations
+        }).clone()
+    }
+
+    fn insert_included(&self, permutations: &mut HashMap<String, Permutation>) {
+        for point in &self.include {
+            let mut arguments: Vec<(String, String)> = point.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            arguments.sort();
+            insert_permutations(arguments, permutations, self.repeat, self.argument_style);
+        }
+    }
+
+    /// Every combination of the argument values, honoring `zip`.
+    fn grid(&self) -> HashMap<String, Permutation> {
         if self.arguments.is_empty() {
             return HashMap::new();
         }
 
-        let combinations: usize = self.arguments.values().map(|v| v.len()).product();
+        let axes = self.axes();
+        let combinations: usize = axes.iter().map(|v| v.len()).product();
         let mut permutations = HashMap::with_capacity(combinations * self.repeat);
 
-        // Convert HashMap to Vec for stable ordering
-        let mut args: Vec<(String, Vec<String>)> = self.arguments.iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-        args.sort_by(|a, b| a.0.cmp(&b.0));
-
         // Generate all permutations recursively
-        generate_recursive(&args, 0, &mut vec![], &mut permutations, self.repeat);
+        generate_recursive(&axes, 0, &mut vec![], &mut permutations, self.repeat, self.argument_style);
 
         permutations
     }
+
+    /// The time limit of `permutation`, if any.
+    pub fn timeout_for(&self, permutation: &Permutation) -> Option<Duration> {
+        self.timeout_overrides.iter()
+            .rev()
+            .find(|o| permutation.matches(&o.arguments))
+            .map(|o| o.timeout)
+            .or(self.timeout)
+            .map(Duration::from_secs)
+    }
+
+    /// The threads `permutation` needs, 0 if it takes a whole node.
+    pub fn threads_for(&self, permutation: &Permutation) -> Result<usize> {
+        Ok(self.threads_per_task.amount_for(permutation, "threads_per_task")? as usize)
+    }
+
+    /// The memory in MiB `permutation` needs, zero if not configured.
+    pub fn memory_for(&self, permutation: &Permutation) -> Result<u64> {
+        match &self.memory_per_task {
+            Some(memory) => memory.amount_for(permutation, "memory_per_task"),
+            None => Ok(0),
+        }
+    }
+
+    /// What a node with `detected_threads` threads and `detected_memory` MiB
+    /// of available memory may run, given that no job needs fewer than
+    /// `min_job_threads` threads, see [`min_job_threads`], and that the
+    /// backend runs at most `backend_max_slots` jobs at a time.
+    pub fn node_capacity(&self, host: &str, detected_threads: usize, detected_memory: Option<u64>, min_job_threads: usize, backend_max_slots: Option<usize>) -> NodeCapacity {
+        let settings = self.host_settings.get(host).cloned().unwrap_or_default();
+        let threads = settings.threads.unwrap_or(detected_threads)
+            .saturating_sub(settings.reserved_cores)
+            .max(1);
+        let memory = settings.memory.or(detected_memory)
+            .map(|memory| memory.saturating_sub(settings.reserved_memory));
+        let slots = threads.checked_div(min_job_threads).unwrap_or(1)
+            .min(settings.max_slots.unwrap_or(usize::MAX))
+            .min(backend_max_slots.unwrap_or(usize::MAX))
+            .max(1);
+        NodeCapacity { threads, memory, slots }
+    }
+
+    /// Argument names in the order they appear in permutation ids.
+    pub fn argument_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.arguments.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Recovers the parameters and the repeat index from a permutation id
+    /// of the form `key1=value1-key2=value2_repeat`.
+    pub fn parse_permutation_id(&self, id: &str) -> Option<(Vec<(String, String)>, usize)> {
+        let (body, repeat) = id.rsplit_once('_')?;
+        let repeat = repeat.parse::<usize>().ok()?;
+
+        let names = self.argument_names();
+        let mut parameters = Vec::with_capacity(names.len());
+        let mut rest = body;
+        for (i, name) in names.iter().enumerate() {
+            rest = rest.strip_prefix(name.as_str())?.strip_prefix('=')?;
+            let value = match names.get(i + 1) {
+                Some(next) => {
+                    let end = rest.find(format!("-{}=", next).as_str())?;
+                    let value = &rest[..end];
+                    rest = &rest[end + 1..];
+                    value
+                }
+                None => std::mem::take(&mut rest),
+            };
+            parameters.push((name.clone(), value.to_string()));
+        }
+        Some((parameters, repeat))
+    }
+}
+
+/// Longest permutation id body kept verbatim; file names are limited to 255 bytes
+const MAX_ID_LENGTH: usize = 200;
+
+/// The threads of the smallest of jobs needing `job_threads`, which size the
+/// slots of a node: 0 if every job takes a whole node, 1 if there are none.
+pub fn min_job_threads(job_threads: impl IntoIterator<Item = usize>) -> usize {
+    let mut any = false;
+    let min = job_threads.into_iter().inspect(|_| any = true).filter(|&threads| threads > 0).min();
+    match min {
+        Some(threads) => threads,
+        None if any => 0,
+        None => 1,
+    }
+}
+
+fn is_id_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '=' | '-' | '_' | '+' | ',')
+}
+
+/// 64 bit FNV-1a, stable across platforms and compiler versions.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Replaces characters that are not safe in directory names and shells with
+/// `_` and shortens overlong ids. Since this can make two ids equal, a hash of
+/// the original id is appended whenever anything was changed.
+fn sanitize_id(raw: &str) -> String {
+    let mut id: String = raw.chars()
+        .map(|c| if is_id_safe(c) { c } else { '_' })
+        .collect();
+    if id == raw && id.len() <= MAX_ID_LENGTH {
+        return id;
+    }
+    id.truncate(MAX_ID_LENGTH);
+    format!("{}~{:016x}", id, fnv1a(raw))
 }
 
 fn generate_recursive(
-    args: &[(String, Vec<String>)],
+    axes: &[Vec<Vec<(String, String)>>],
     index: usize,
     current: &mut Vec<(String, String)>,
-    permutations: &mut HashMap<String, String>,
+    permutations: &mut HashMap<String, Permutation>,
     repeat: usize,
+    style: ArgumentStyle,
 ) {
-    if index == args.len() {
-        // Build id and parameters from current combination
-        let id = current.iter()
-            .map(|(key, value)| format!("{}={}", key, value))
-            .collect::<Vec<_>>()
-            .join("-");
-
-        let parameters = current.iter()
-            .map(|(key, value)| format!("--{}={}", key, value))
-            .collect::<Vec<_>>()
-            .join(" ");
-
-        for i in 0..repeat {
-            let id_with_repeat = format!("{}_{}", id, i);
-            permutations.insert(id_with_repeat, parameters.clone());
-        }
+    if index == axes.len() {
+        // Ids and argv list the arguments by name, wherever zip groups put them
+        let mut current = current.clone();
+        current.sort();
+        insert_permutations(current, permutations, repeat, style);
         return;
     }
 
-    let (key, values) = &args[index];
-    for value in values {
-        current.push((key.clone(), value.clone()));
-        generate_recursive(args, index + 1, current, permutations, repeat);
-        current.pop();
+    for assignments in &axes[index] {
+        current.extend(assignments.iter().cloned());
+        generate_recursive(axes, index + 1, current, permutations, repeat, style);
+        current.truncate(current.len() - assignments.len());
+    }
+}
+
+/// The id of the arguments `current`, sorted by name, without the repeat suffix.
+fn permutation_id(current: &[(String, String)]) -> String {
+    sanitize_id(&current.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("-"))
+}
+
+/// Adds the `repeat` jobs of the arguments `current`, sorted by name.
+fn insert_permutations(
+    current: Vec<(String, String)>,
+    permutations: &mut HashMap<String, Permutation>,
+    repeat: usize,
+    style: ArgumentStyle,
+) {
+    let id = permutation_id(&current);
+
+    let argv: Vec<String> = current.iter()
+        .flat_map(|(key, value)| match style {
+            ArgumentStyle::Joined => vec![format!("--{}={}", key, value)],
+            ArgumentStyle::Separate => vec![format!("--{}", key), value.clone()],
+        })
+        .collect();
+
+    for i in 0..repeat {
+        let id_with_repeat = format!("{}_{}", id, i);
+        permutations.insert(id_with_repeat.clone(), Permutation {
+            id: id_with_repeat,
+            argv: argv.clone(),
+            arguments: current.clone(),
+            repeat: i,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_ids_are_kept() {
+        assert_eq!(sanitize_id("a=1-b=x.y"), "a=1-b=x.y");
+    }
+
+    #[test]
+    fn unsafe_characters_are_replaced_and_hashed() {
+        for raw in ["a=x/y", "a=../..", "a=é", "a=日本", "a=$(id)", "a=x y"] {
+            let id = sanitize_id(raw);
+            let (body, hash) = id.rsplit_once('~').unwrap();
+            assert!(body.chars().all(is_id_safe), "{} became {}", raw, id);
+            assert_eq!(hash.len(), 16);
+            assert!(!id.contains('/'));
+        }
+        assert_eq!(sanitize_id("a=x/y").split('~').next(), Some("a=x_y"));
+    }
+
+    #[test]
+    fn dots_never_form_a_path_component() {
+        // Ids start with an argument name, so they are never `.` or `..`
+        assert_eq!(sanitize_id("a=.."), "a=..");
+        assert_eq!(Path::new(&sanitize_id("a=../..")).components().count(), 1);
+    }
+
+    #[test]
+    fn ids_that_sanitize_alike_stay_distinct() {
+        assert_ne!(sanitize_id("a=x/y"), sanitize_id("a=x y"));
+        assert_ne!(sanitize_id("a=é"), sanitize_id("a=ü"));
+        assert_ne!(sanitize_id("a=x/y"), "a=x_y");
+    }
+
+    fn config(extra: &str) -> Config {
+        grid_config(extra, "a = [1]")
+    }
+
+    /// A config with the settings `extra` and the `[arguments]` table `arguments`, not validated.
+    fn grid_config(extra: &str, arguments: &str) -> Config {
+        toml::from_str(&format!("name = \"t\"\nworkdir = \"wd\"\nexecutable = \"run.sh\"\nrepeat = 1\n{}\n[arguments]\n{}\n", extra, arguments)).unwrap()
+    }
+
+    /// A config with one thread per job, the settings `extra` and the `[arguments]` table `arguments`.
+    fn one_thread(extra: &str, arguments: &str) -> Config {
+        grid_config(&format!("threads_per_task = 1\n{}", extra), arguments)
+    }
+
+    /// A config with one thread per job, the zip groups `zip` and the `[arguments]` table `arguments`.
+    fn zipped(zip: &str, arguments: &str) -> Config {
+        one_thread(&format!("zip = {}", zip), arguments)
+    }
+
+    /// The argument assignments of the jobs of `config`, sorted.
+    fn points(config: &Config) -> Vec<String> {
+        let mut points: Vec<String> = config.get_arguments_permutations().into_values()
+            .map(|permutation| permutation.arguments.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join(" "))
+            .collect();
+        points.sort();
+        points
+    }
+
+    fn validate_error(config: &Config) -> String {
+        format!("{:#}", config.validate().unwrap_err())
+    }
+
+    #[test]
+    fn zipped_arguments_vary_together_and_cross_the_others() {
+        let config = zipped("[[\"a\", \"b\"]]", "a = [1, 2]\nb = [\"x\", \"y\"]\nc = [true, false]");
+        config.validate().unwrap();
+        assert_eq!(points(&config), [
+            "a=1 b=x c=false", "a=1 b=x c=true",
+            "a=2 b=y c=false", "a=2 b=y c=true",
+        ]);
+    }
+
+    #[test]
+    fn empty_zip_groups_are_ignored() {
+        let config = zipped("[[]]", "a = [1, 2]");
+        config.validate().unwrap();
+        assert_eq!(points(&config), ["a=1", "a=2"]);
+    }
+
+    #[test]
+    fn zipped_arguments_need_equal_lengths() {
+        let config = zipped("[[\"a\", \"b\"]]", "a = [1, 2]\nb = [\"x\"]");
+        assert!(validate_error(&config).contains("zipped arguments need the same number of values: a has 2, b has 1"));
+    }
+
+    #[test]
+    fn zip_groups_reject_duplicate_rows() {
+        let config = zipped("[[\"a\", \"b\"]]", "a = [1, 1, 2]\nb = [\"x\", \"x\", \"y\"]");
+        assert!(validate_error(&config).contains("more than once"));
+        // A value may repeat as long as the rows differ
+        let config = zipped("[[\"a\", \"b\"]]", "a = [1, 1]\nb = [\"x\", \"y\"]");
+        config.validate().unwrap();
+        assert_eq!(points(&config), ["a=1 b=x", "a=1 b=y"]);
+    }
+
+    #[test]
+    fn arguments_are_in_one_zip_group_at_most() {
+        let config = zipped("[[\"a\", \"b\"], [\"b\", \"c\"]]", "a = [1]\nb = [2]\nc = [3]");
+        assert!(validate_error(&config).contains("argument b is in more than one zip group"));
+        let config = zipped("[[\"a\", \"d\"]]", "a = [1]");
+        assert!(validate_error(&config).contains("zip names d, which is not an argument"));
+    }
+
+    #[test]
+    fn exclude_compares_numbers() {
+        let config = one_thread("exclude = [\"a >= 2.5 || a > 1 && b == \\\"y\\\"\"]", "a = [1, 2, 3]\nb = [\"x\", \"y\"]");
+        config.validate().unwrap();
+        assert_eq!(points(&config), ["a=1 b=x", "a=1 b=y", "a=2 b=x"]);
+    }
+
+    #[test]
+    fn include_adds_points_after_exclude() {
+        let config = one_thread("exclude = [\"a == 1\"]\ninclude = [{ a = 1, b = \"x\" }, { a = 9, b = \"z\" }]", "a = [1, 2]\nb = [\"x\"]");
+        config.validate().unwrap();
+        assert_eq!(points(&config), ["a=1 b=x", "a=2 b=x", "a=9 b=z"]);
+    }
+
+    #[test]
+    fn include_points_assign_every_argument() {
+        let config = one_thread("include = [{ a = 1 }]", "a = [1]\nb = [2]");
+        assert!(validate_error(&config).contains("has to assign exactly the arguments a, b"));
+        let config = one_thread("include = [{ a = 1, b = 2, c = 3 }]", "a = [1]\nb = [2]");
+        assert!(validate_error(&config).contains("has to assign exactly the arguments a, b"));
+    }
+
+    #[test]
+    fn exclude_with_unknown_variables_fails_at_load() {
+        let config = one_thread("exclude = [\"c > 1\"]", "a = [1, 2]");
+        assert!(validate_error(&config).contains("failed to evaluate exclude expression `c > 1`"));
+    }
+
+    #[test]
+    fn slots_are_limited_by_threads_settings_and_backend() {
+        let config = config("threads_per_task = 2\n[host_settings.small]\nmax_slots = 2\n");
+        assert_eq!(config.node_capacity("big", 16, None, 2, None).slots, 8);
+        assert_eq!(config.node_capacity("small", 16, None, 2, None).slots, 2);
+        // Slurm sizes its threads for max_jobs of the largest jobs
+        assert_eq!(config.node_capacity("slurm", 3 * 8, None, 2, Some(3)).slots, 3);
+        assert_eq!(config.node_capacity("big", 1, None, 4, None).slots, 1);
+    }
+
+    #[test]
+    fn zero_threads_per_task_takes_a_whole_node() {
+        let config = config("threads_per_task = 0");
+        assert!(config.validate().is_ok());
+        let permutation = config.get_arguments_permutations().into_values().next().unwrap();
+        assert_eq!(config.threads_for(&permutation).unwrap(), 0);
+        let capacity = config.node_capacity("big", 16, None, 0, None);
+        assert_eq!((capacity.slots, capacity.threads), (1, 16));
+    }
+
+    #[test]
+    fn whole_node_jobs_do_not_size_slots() {
+        assert_eq!(min_job_threads([4, 0, 2]), 2);
+        assert_eq!(min_job_threads([0, 0]), 0);
+        assert_eq!(min_job_threads([]), 1);
+    }
+
+    #[test]
+    fn long_ids_are_shortened() {
+        let raw = format!("a={}", "x".repeat(300));
+        let id = sanitize_id(&raw);
+        assert!(id.len() <= MAX_ID_LENGTH + 17);
+        assert_ne!(id, sanitize_id(&format!("a={}", "x".repeat(301))));
     }
 }
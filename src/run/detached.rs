@@ -0,0 +1,79 @@
+//! Jobs that run detached from the controller.
+//!
+//! Normally a job lives as long as the command that started it, so it dies
+//! with the SSH connection. A detached job is started with `nohup setsid`
+//! in the background instead; its pid, start time, output and exit status
+//! go to files in its job directory, which the controller polls. A later
+//! controller can pick up a job that is still running from those files.
+//!
+//! The job outlives a lost connection, so following it reconnects rather
+//! than giving up; only if that fails is the job left for a later run.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use spdlog::prelude::*;
+use super::backend::{execute_reconnecting, Backend, JobOutcome, JobOutput, JobRun, Scratch};
+use super::commands::shell_quote;
+
+/// Time between two looks at the files of a detached job
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Starts `job` detached on `backend` and waits for it.
+pub async fn run(backend: &dyn Backend, scratch: &Scratch, job: &JobRun<'_>) -> Result<JobOutcome> {
+	let output = backend.execute(&scratch.detached_command(job)).await?;
+	if output.exit_status != 0 {
+		anyhow::bail!("failed to start task {} detached: {}", job.id, output.stderr.trim());
+	}
+	follow(backend, scratch, job).await
+}
+
+/// Follows `job` if an earlier controller started it detached. Otherwise
+/// whatever is left of it is killed and `None` returned.
+pub async fn reattach(backend: &dyn Backend, scratch: &Scratch, job: &JobRun<'_>) -> Result<Option<JobOutcome>> {
+	let started = execute_reconnecting(backend, &format!("test -f {}", shell_quote(&scratch.started_file(job.id)))).await?;
+	if !job.detached || started.exit_status != 0 {
+		backend.kill_job(job).await?;
+		return Ok(None);
+	}
+	info!("Reattached to task {} on {}", job.id, backend.name());
+	follow(backend, scratch, job).await.map(Some)
+}
+
+/// Whether the detached job `id` is still running on `backend`.
+pub async fn is_running(backend: &dyn Backend, scratch: &Scratch, id: &str) -> Result<bool> {
+	let poll = execute_reconnecting(backend, &scratch.detached_poll_command(id)).await?;
+	Ok(poll.stdout.starts_with("running"))
+}
+
+/// Polls `job` until it exits, killing it once it runs into its timeout.
+async fn follow(backend: &dyn Backend, scratch: &Scratch, job: &JobRun<'_>) -> Result<JobOutcome> {
+	loop {
+		let poll = execute_reconnecting(backend, &scratch.detached_poll_command(job.id)).await?;
+		let line = poll.stdout.trim();
+		match line.split_once(' ').unwrap_or((line, "")) {
+			("exited", exit_status) => {
+				let exit_status = exit_status.trim().parse::<u32>()
+					.with_context(|| format!("Invalid exit status of task {}: {}", job.id, exit_status))?;
+				return Ok(JobOutcome::Exited(JobOutput {
+					exit_status,
+					stdout: read(backend, &scratch.stdout_file(job.id)).await?,
+					stderr: read(backend, &scratch.stderr_file(job.id)).await?,
+				}));
+			}
+			("running", elapsed) => {
+				let elapsed = Duration::from_secs(elapsed.trim().parse().unwrap_or(0));
+				if job.timeout.is_some_and(|timeout| elapsed >= timeout) {
+					backend.kill_job(job).await?;
+					return Ok(JobOutcome::TimedOut);
+				}
+			}
+			("lost", _) => anyhow::bail!("task {} is gone from {} without an exit status", job.id, backend.name()),
+			_ => anyhow::bail!("unexpected state of task {} on {}: {} {}", job.id, backend.name(), line, poll.stderr.trim()),
+		}
+		tokio::time::sleep(POLL_INTERVAL).await;
+	}
+}
+
+async fn read(backend: &dyn Backend, path: &str) -> Result<String> {
+	Ok(execute_reconnecting(backend, &format!("cat {} 2>/dev/null", shell_quote(path))).await?.stdout)
+}
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use async_ssh2_tokio::ServerCheckMethod;
+use russh::keys::PublicKey;
+use russh::keys::known_hosts::{known_host_keys_path, learn_known_hosts_path};
+use spdlog::prelude::*;
+
+/// The `StrictHostKeyChecking` setting of ssh_config. `ask` behaves like
+/// `yes`, since nodes are connected to concurrently without a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrictHostKeyChecking {
+	Yes,
+	AcceptNew,
+	No,
+}
+
+impl StrictHostKeyChecking {
+	pub fn parse(value: &str) -> Self {
+		match value.to_ascii_lowercase().as_str() {
+			"accept-new" => StrictHostKeyChecking::AcceptNew,
+			"no" | "off" => StrictHostKeyChecking::No,
+			_ => StrictHostKeyChecking::Yes,
+		}
+	}
+
+	pub fn as_ssh_option(&self) -> &'static str {
+		match self {
+			StrictHostKeyChecking::Yes => "yes",
+			StrictHostKeyChecking::AcceptNew => "accept-new",
+			StrictHostKeyChecking::No => "no",
+		}
+	}
+}
+
+pub fn default_known_hosts_file() -> Option<PathBuf> {
+	Some(home::home_dir()?.join(".ssh/known_hosts"))
+}
+
+/// Handshake handler that records the server key and then aborts the connection.
+struct KeyProbe(Arc<Mutex<Option<PublicKey>>>);
+
+impl russh::client::Handler for KeyProbe {
+	type Error = russh::Error;
+
+	async fn check_server_key(&mut self, server_public_key: &PublicKey) -> Result<bool, Self::Error> {
+		*self.0.lock().expect("key probe mutex poisoned") = Some(server_public_key.clone());
+		Ok(false)
+	}
+}
+
+async fn probe_host_key(address: SocketAddr) -> Result<PublicKey> {
+	let key = Arc::new(Mutex::new(None));
+	let config = Arc::new(russh::client::Config::default());
+	// The handshake is expected to fail once the key has been seen
+	let result = russh::client::connect(config, address, KeyProbe(key.clone())).await;
+	let key = key.lock().expect("key probe mutex poisoned").take();
+	match (key, result) {
+		(Some(key), _) => Ok(key),
+		(None, Err(err)) => Err(err).with_context(|| format!("Failed to read the host key from {}", address)),
+		(None, Ok(_)) => anyhow::bail!("Failed to read the host key from {}", address),
+	}
+}
+
+/// Checks `key` against the entries for `host` in `known_hosts`. Unknown
+/// hosts are refused under `StrictHostKeyChecking yes`; otherwise their key
+/// is recorded, as ssh does for `accept-new`. A changed key is always refused.
+fn verify_host_key(host: &str, port: u16, key: &PublicKey, known_hosts: &Path, strict: StrictHostKeyChecking) -> Result<()> {
+	let recorded = known_host_keys_path(host, port, known_hosts)
+		.with_context(|| format!("Failed to read {}", known_hosts.display()))?;
+
+	if recorded.iter().any(|(_, recorded)| recorded == key) {
+		return Ok(());
+	}
+	if let Some((line, _)) = recorded.iter().find(|(_, recorded)| recorded.algorithm() == key.algorithm()) {
+		anyhow::bail!("The {} host key of {} does not match {}:{}. Someone could be eavesdropping, refusing to connect",
+			key.algorithm(), host, known_hosts.display(), line);
+	}
+	if strict == StrictHostKeyChecking::Yes {
+		anyhow::bail!("No {} host key for {} in {}. Connect once with ssh to verify it, set StrictHostKeyChecking accept-new or pass --insecure-accept-host-keys",
+			key.algorithm(), host, known_hosts.display());
+	}
+	learn_known_hosts_path(host, port, key, known_hosts)
+		.with_context(|| format!("Failed to add the host key of {} to {}", host, known_hosts.display()))?;
+	warn!("Permanently added the {} host key of {} to {}", key.algorithm(), host, known_hosts.display());
+	Ok(())
+}
+
+/// Reads the host key served at `address`, verifies it as the key of
+/// `host`:`port` and pins it for the actual connection. `address` differs from
+/// `host` when the connection goes through a ProxyJump tunnel.
+pub async fn server_check_method(address: SocketAddr, host: &str, port: u16, known_hosts: &Path, strict: StrictHostKeyChecking) -> Result<ServerCheckMethod> {
+	let key = probe_host_key(address).await?;
+	verify_host_key(host, port, &key, known_hosts, strict)?;
+	let openssh = key.to_openssh().context("Failed to encode host key")?;
+	let base64 = openssh.split_whitespace().nth(1).context("Failed to encode host key")?;
+	Ok(ServerCheckMethod::PublicKey(base64.to_string()))
+}
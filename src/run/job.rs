@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+use crate::run::config_file::Permutation;
+
+/// A permutation waiting in the queue, together with its retry state.
+#[derive(Debug, Clone)]
+pub struct Job {
+	pub permutation: Permutation,
+	/// Threads the job needs
+	pub threads: usize,
+	/// Memory in MiB the job needs
+	pub memory: u64,
+	/// Number of attempts that have already been made
+	pub attempt: usize,
+	/// Host of the last failed attempt, which the retry should avoid
+	pub last_host: Option<String>,
+	/// How often the job was put back because it popped up on `last_host`
+	pub deferrals: usize,
+	/// The job is not started before this instant
+	pub not_before: Option<Instant>,
+	/// Host on which an earlier controller left the job running
+	pub reattach_host: Option<String>,
+}
+
+impl Job {
+	pub fn new(permutation: Permutation, threads: usize, memory: u64) -> Self {
+		Self {
+			permutation,
+			threads,
+			memory,
+			attempt: 0,
+			last_host: None,
+			deferrals: 0,
+			not_before: None,
+			reattach_host: None,
+		}
+	}
+
+	/// Turns a failed attempt on `hostname` into a retry that starts after
+	/// `backoff`, doubled for every previous retry.
+	pub fn retry(mut self, hostname: &str, backoff: Duration) -> Self {
+		let delay = backoff.saturating_mul(1 << self.attempt.min(16));
+		self.attempt += 1;
+		self.last_host = Some(hostname.to_string());
+		self.deferrals = 0;
+		self.not_before = Some(Instant::now() + delay);
+		self.reattach_host = None;
+		self
+	}
+
+	pub fn is_ready(&self) -> bool {
+		self.not_before.is_none_or(|t| Instant::now() >= t)
+	}
+
+	/// Whether a worker on `hostname` should leave the job to another node.
+	/// A job is handed around at most `node_count` times before it runs on
+	/// whichever node picks it up.
+	pub fn should_avoid(&self, hostname: &str, node_count: usize) -> bool {
+		node_count > 1
+			&& self.deferrals < node_count
+			&& self.last_host.as_deref() == Some(hostname)
+	}
+}
@@ -0,0 +1,212 @@
+//! Append-only journal of a run, kept in the results folder.
+//!
+//! Every line of [`JOURNAL_FILE`] is a JSON object with the time of an event
+//! in seconds since the Unix epoch and the event itself. Runs of the same
+//! config append to the same journal, so it doubles as an audit log, and a
+//! new run replays it to pick up the attempts and the still running jobs of
+//! a controller that died or was interrupted. A run that ended without
+//! interruption leaves nothing to pick up, so replay starts over after it.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use spdlog::prelude::*;
+
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+	RunStarted { jobs: usize },
+	/// A job was handed to `host`, `attempt` counting from zero
+	Dispatched { id: String, host: String, attempt: usize },
+	Exited { id: String, host: String, exit_status: u32 },
+	TimedOut { id: String, host: String },
+	/// Running the job failed before it could exit, e.g. on an SSH error
+	Errored { id: String, host: String, error: String },
+	/// The results were fetched from `host`, unless there is an `error`
+	Transferred { id: String, host: String, error: Option<String> },
+	/// The job was queued again as attempt `attempt`
+	Retried { id: String, attempt: usize },
+	/// The job was killed because the run was aborted
+	Killed { id: String, host: String },
+	/// The completion marker was written with `status`
+	Completed { id: String, status: String },
+	RunEnded { interrupted: bool },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+	time: f64,
+	#[serde(flatten)]
+	event: Event,
+}
+
+pub struct Journal {
+	file: Mutex<File>,
+}
+
+impl Journal {
+	pub fn open(results_path: &Path) -> Result<Self> {
+		let path = results_path.join(JOURNAL_FILE);
+		let file = OpenOptions::new().create(true).append(true).open(&path)
+			.with_context(|| format!("failed to open {}", path.display()))?;
+		Ok(Self { file: Mutex::new(file) })
+	}
+
+	/// Appends `event`. Failures are logged rather than failing the run.
+	pub fn record(&self, event: Event) {
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default();
+		let line = match serde_json::to_string(&Record { time, event }) {
+			Ok(line) => line,
+			Err(err) => {
+				error!("Failed to serialize a journal event\n{}", err);
+				return;
+			}
+		};
+		let mut file = self.file.lock().expect("journal mutex poisoned");
+		if let Err(err) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+			error!("Failed to write to the journal\n{}", err);
+		}
+	}
+}
+
+/// State of the jobs as recorded by the journal.
+#[derive(Debug, Default)]
+pub struct Replay {
+	/// Attempts made so far by jobs that did not complete, by id, since the
+	/// last run that ended without interruption
+	pub attempts: HashMap<String, usize>,
+	/// Jobs dispatched without a recorded end, with the host they ran on
+	pub running: HashMap<String, String>,
+	/// Host of the last dispatch of every job, by id
+	pub hosts: HashMap<String, String>,
+}
+
+impl Replay {
+	/// Replays the journal in `results_path`, if there is one. A line cut
+	/// short by a crash is skipped.
+	pub fn load(results_path: &Path) -> Result<Self> {
+		let path = results_path.join(JOURNAL_FILE);
+		let content = match fs::read_to_string(&path) {
+			Ok(content) => content,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+			Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+		};
+		let mut replay = Self::default();
+		for (number, line) in content.lines().enumerate() {
+			match serde_json::from_str::<Record>(line) {
+				Ok(record) => replay.apply(record.event),
+				Err(err) => warn!("Skipping line {} of {}\n{}", number + 1, path.display(), err),
+			}
+		}
+		Ok(replay)
+	}
+
+	fn apply(&mut self, event: Event) {
+		match event {
+			Event::Dispatched { id, host, attempt } => {
+				self.attempts.insert(id.clone(), attempt);
+				self.hosts.insert(id.clone(), host.clone());
+				self.running.insert(id, host);
+			}
+			Event::Exited { id, .. }
+			| Event::TimedOut { id, .. }
+			| Event::Errored { id, .. }
+			| Event::Killed { id, .. } => {
+				self.running.remove(&id);
+			}
+			Event::Retried { id, attempt } => {
+				self.attempts.insert(id, attempt);
+			}
+			Event::Completed { id, .. } => {
+				self.attempts.remove(&id);
+				self.running.remove(&id);
+			}
+			Event::RunEnded { interrupted: false } => {
+				self.attempts.clear();
+				self.running.clear();
+			}
+			Event::RunStarted { .. } | Event::Transferred { .. } | Event::RunEnded { .. } => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn replay(events: Vec<Event>) -> Replay {
+		let mut replay = Replay::default();
+		for event in events {
+			replay.apply(event);
+		}
+		replay
+	}
+
+	fn dispatched(id: &str, attempt: usize) -> Event {
+		Event::Dispatched { id: id.to_string(), host: "a".to_string(), attempt }
+	}
+
+	fn errored(id: &str) -> Event {
+		Event::Errored { id: id.to_string(), host: "a".to_string(), error: "lost".to_string() }
+	}
+
+	#[test]
+	fn crashed_runs_leave_attempts_and_running_jobs() {
+		let replay = replay(vec![
+			Event::RunStarted { jobs: 2 },
+			dispatched("x", 0),
+			errored("x"),
+			Event::Retried { id: "x".to_string(), attempt: 1 },
+			dispatched("y", 0),
+		]);
+		assert_eq!(replay.attempts["x"], 1);
+		assert_eq!(replay.running.get("y").map(String::as_str), Some("a"));
+		assert!(!replay.running.contains_key("x"));
+	}
+
+	#[test]
+	fn interrupted_runs_carry_attempts_over() {
+		let replay = replay(vec![
+			dispatched("x", 0),
+			errored("x"),
+			Event::Retried { id: "x".to_string(), attempt: 1 },
+			Event::RunEnded { interrupted: true },
+			Event::RunStarted { jobs: 1 },
+		]);
+		assert_eq!(replay.attempts["x"], 1);
+	}
+
+	#[test]
+	fn finished_runs_give_exhausted_jobs_fresh_attempts() {
+		let replay = replay(vec![
+			dispatched("x", 0),
+			errored("x"),
+			Event::Retried { id: "x".to_string(), attempt: 1 },
+			dispatched("x", 1),
+			errored("x"),
+			Event::RunEnded { interrupted: false },
+			Event::RunStarted { jobs: 1 },
+		]);
+		assert!(replay.attempts.is_empty());
+		assert!(replay.running.is_empty());
+		assert_eq!(replay.hosts["x"], "a");
+	}
+
+	#[test]
+	fn completed_jobs_are_forgotten() {
+		let replay = replay(vec![
+			dispatched("x", 0),
+			Event::Exited { id: "x".to_string(), host: "a".to_string(), exit_status: 0 },
+			Event::Completed { id: "x".to_string(), status: "succeeded".to_string() },
+		]);
+		assert!(replay.attempts.is_empty());
+		assert!(replay.running.is_empty());
+	}
+}
@@ -0,0 +1,207 @@
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Stdio;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use spdlog::prelude::*;
+use tokio::fs;
+use tokio::process::Command;
+use super::backend::{available_memory, Backend, JobOutcome, JobOutput, JobRun, Scratch};
+use super::commands;
+use super::detached;
+
+/// Runs jobs as child processes on this machine, without SSH.
+pub struct LocalNode {
+	threads: usize,
+	memory: Option<u64>,
+	scratch: Scratch,
+}
+
+impl LocalNode {
+	pub fn new(scratch: Scratch) -> Result<Self> {
+		let threads = std::thread::available_parallelism().context("failed to query for threads")?.get();
+		let memory = std::fs::read_to_string("/proc/meminfo").ok().and_then(|meminfo| available_memory(&meminfo));
+		if memory.is_none() {
+			warn!("Failed to read the available memory of localhost, memory_per_task is not enforced on it");
+		}
+		Ok(Self { threads, memory, scratch })
+	}
+
+	/// A POSIX shell running `command` that leads its own process group.
+	fn shell(command: &str) -> Command {
+		let mut shell = Command::new("sh");
+		shell.arg("-c")
+			.arg(command)
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.process_group(0);
+		shell
+	}
+
+	/// Runs `command` with a POSIX shell that leads its own process group.
+	async fn run_shell(command: &str) -> Result<JobOutput> {
+		let output = Self::shell(command).output().await.context("Failed to start sh")?;
+		Ok(Self::job_output(output))
+	}
+
+	fn job_output(output: std::process::Output) -> JobOutput {
+		let exit_status = match (output.status.code(), output.status.signal()) {
+			(Some(code), _) => code as u32,
+			(None, Some(signal)) => 128 + signal as u32,
+			(None, None) => u32::MAX,
+		};
+		JobOutput {
+			exit_status,
+			stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+			stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+		}
+	}
+}
+
+#[async_trait]
+impl Backend for LocalNode {
+	fn name(&self) -> &str {
+		"localhost"
+	}
+
+	fn threads(&self) -> usize {
+		self.threads
+	}
+
+	fn memory(&self) -> Option<u64> {
+		self.memory
+	}
+
+	async fn execute(&self, command: &str) -> Result<JobOutput> {
+		Self::run_shell(command).await
+	}
+
+	async fn prepare_workdir(&self, workdir: &str) -> Result<()> {
+		commands::copy_dir(workdir, &self.scratch.workdir(), false).await
+	}
+
+	async fn run_job(&self, job: &JobRun<'_>) -> Result<JobOutcome> {
+		if job.detached {
+			return detached::run(self, &self.scratch, job).await;
+		}
+		let child = Self::shell(&self.scratch.job_command(job)).spawn().context("Failed to start sh")?;
+		let output = child.wait_with_output();
+		tokio::pin!(output);
+		let Some(timeout) = job.timeout else {
+			return Ok(JobOutcome::Exited(Self::job_output(output.await?)));
+		};
+		match tokio::time::timeout(timeout, &mut output).await {
+			Ok(output) => Ok(JobOutcome::Exited(Self::job_output(output?))),
+			Err(_) => {
+				// The shell is reaped while it is killed, or the kill would wait
+				// for the zombie it leaves until the grace period is over
+				let (killed, _) = tokio::join!(self.kill_job(job), &mut output);
+				if let Err(err) = killed {
+					error!("failed to kill task {} on {}\n{}", job.id, self.name(), err);
+				}
+				Ok(JobOutcome::TimedOut)
+			}
+		}
+	}
+
+	async fn reattach(&self, job: &JobRun<'_>) -> Result<Option<JobOutcome>> {
+		detached::reattach(self, &self.scratch, job).await
+	}
+
+	async fn is_running(&self, id: &str) -> Result<bool> {
+		detached::is_running(self, &self.scratch, id).await
+	}
+
+	async fn kill_job(&self, job: &JobRun<'_>) -> Result<()> {
+		let output = Self::run_shell(&self.scratch.kill_command(job)).await?;
+		if output.exit_status != 0 {
+			anyhow::bail!("kill failed: {}", output.stderr);
+		}
+		Ok(())
+	}
+
+	async fn fetch_results(&self, id: &str, destination: &Path) -> Result<()> {
+		let destination = destination.to_str().context("result path is not valid UTF-8")?;
+		commands::copy_dir(&self.scratch.result_dir(id), destination, true).await
+	}
+
+	async fn cleanup(&self) -> Result<()> {
+		match fs::remove_dir_all(self.scratch.root()).await {
+			Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::PermissionsExt;
+	use std::time::{Duration, Instant};
+
+	/// A node whose scratch directory is in `dir` and whose workdir holds
+	/// `run.sh` with `script`.
+	async fn node(dir: &Path, script: &str) -> LocalNode {
+		let workdir = dir.join("workdir");
+		std::fs::create_dir_all(&workdir).unwrap();
+		std::fs::write(workdir.join("run.sh"), script).unwrap();
+		std::fs::set_permissions(workdir.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+		let node = LocalNode::new(Scratch::with_root(dir.join("scratch").to_str().unwrap().to_string())).unwrap();
+		node.prepare_workdir(workdir.to_str().unwrap()).await.unwrap();
+		node
+	}
+
+	fn job<'a>(argv: &'a [String], timeout: Option<Duration>) -> JobRun<'a> {
+		JobRun { id: "a=1_0", executable: "run.sh", argv, timeout, threads: 1, memory: None, container: None, detached: false }
+	}
+
+	#[tokio::test]
+	async fn jobs_run_in_their_result_directory() {
+		let dir = tempfile::tempdir().unwrap();
+		let node = node(dir.path(), "#!/bin/sh\necho \"$@\" > out.txt\necho done\nexit 3\n").await;
+		let argv = ["--a=1".to_string()];
+		let Ok(JobOutcome::Exited(output)) = node.run_job(&job(&argv, None)).await else {
+			panic!("job did not exit");
+		};
+		assert_eq!(output.exit_status, 3);
+		assert_eq!(output.stdout, "done\n");
+
+		let destination = dir.path().join("results/a=1_0");
+		node.fetch_results("a=1_0", &destination).await.unwrap();
+		assert_eq!(std::fs::read_to_string(destination.join("out.txt")).unwrap(), "--a=1\n");
+		assert!(!Path::new(&node.scratch.result_dir("a=1_0")).exists());
+	}
+
+	#[tokio::test]
+	async fn jobs_are_killed_at_their_timeout() {
+		let dir = tempfile::tempdir().unwrap();
+		let node = node(dir.path(), "#!/bin/sh\nsleep 30 &\nwait\n").await;
+		let start = Instant::now();
+		let outcome = node.run_job(&job(&[], Some(Duration::from_secs(1)))).await.unwrap();
+		assert!(matches!(outcome, JobOutcome::TimedOut));
+		// Jobs that go on SIGTERM are not waited for until the grace period is over
+		assert!(start.elapsed() < Duration::from_secs(4));
+		let pid = std::fs::read_to_string(node.scratch.pid_file("a=1_0")).unwrap();
+		let alive = LocalNode::run_shell(&format!("pgrep -s {}", pid.trim())).await.unwrap();
+		assert_ne!(alive.exit_status, 0, "left running: {}", alive.stdout);
+	}
+
+	#[tokio::test]
+	async fn killed_jobs_stop() {
+		let dir = tempfile::tempdir().unwrap();
+		let node = node(dir.path(), "#!/bin/sh\nsleep 30\n").await;
+		let job = job(&[], None);
+		let start = Instant::now();
+		let (outcome, killed) = tokio::join!(node.run_job(&job), async {
+			tokio::time::sleep(Duration::from_millis(500)).await;
+			node.kill_job(&job).await
+		});
+		killed.unwrap();
+		let Ok(JobOutcome::Exited(output)) = outcome else {
+			panic!("job did not exit");
+		};
+		assert_ne!(output.exit_status, 0);
+		assert!(start.elapsed() < Duration::from_secs(3));
+	}
+}
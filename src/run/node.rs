@@ -1,90 +1,475 @@
 use async_ssh2_tokio::client::{Client, AuthMethod, ServerCheckMethod};
+use async_ssh2_tokio::ToSocketAddrsWithHostname;
+use std::collections::HashMap;
 use std::env;
-use ssh_config::SSHConfig;
-use std::fs;
-use home;
-use self_cell::self_cell;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::process::Command;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
 use anyhow::{Context, Result};
-use super::commands;
+use async_trait::async_trait;
+use spdlog::prelude::*;
+use super::{commands, detached, host_keys};
+use super::backend::{available_memory, Backend, Disconnected, JobOutcome, JobOutput, JobRun, Scratch, MEMINFO_COMMAND};
+use super::commands::RemoteShell;
+use super::config_file::{self, AuthKind, HostAuth};
+use super::host_keys::StrictHostKeyChecking;
+use super::ssh_options::{self, SshConfig};
 
-self_cell!(
-	struct SshConfigCell{
-		owner: String,
-
-		#[covariant]
-		dependent: SSHConfig,
-	}
-);
 pub struct NodeCommon {
 	username: String,
-	ssh_config: Option<SshConfigCell>
+	ssh_config: Option<SshConfig>,
+	insecure_accept_host_keys: bool,
+	/// Keys given on the command line, tried for every host
+	identity_files: Vec<PathBuf>,
+	auth: HashMap<String, HostAuth>,
+	/// Passphrases of the keys read so far, `None` for unencrypted keys
+	passphrases: Mutex<HashMap<PathBuf, Option<String>>>,
+	/// Keeps nodes that connect concurrently from prompting at the same time
+	prompt_lock: AsyncMutex<()>,
+}
+
+/// The credential a node logged in with, which rsync logs in with as well.
+#[derive(Debug, Clone)]
+enum Login {
+	Key { path: PathBuf, passphrase: Option<String> },
+	Agent,
+	Password(String),
+}
+
+impl Login {
+	fn auth_method(&self) -> AuthMethod {
+		match self {
+			Login::Key { path, passphrase } => AuthMethod::with_key_file(path, passphrase.as_deref()),
+			Login::Agent => AuthMethod::Agent,
+			Login::Password(password) => AuthMethod::with_password(password),
+		}
+	}
 }
 
-fn get_ssh_config_cell() -> Option<SshConfigCell> {
-    let mut ssh_config_path = home::home_dir()?;
-    ssh_config_path.push(".ssh/config");
-    let config_string = fs::read_to_string(ssh_config_path).ok()?;
-	SshConfigCell::try_new(config_string, |s| SSHConfig::parse_str(s)).ok()
+/// Connection parameters of a host after applying `~/.ssh/config`.
+#[derive(Debug, Clone)]
+pub struct SshTarget {
+	/// The name the host was given by, which ssh_config is queried with
+	pub alias: String,
+	pub host_name: String,
+	pub port: u16,
+	pub user: String,
+	pub identity_files: Vec<PathBuf>,
+	pub auth_methods: Vec<AuthKind>,
+	/// Jump hosts in the order they are passed through, as in `ssh -J`
+	pub proxy_jump: Vec<String>,
+	pub connect_timeout: Option<Duration>,
+	pub known_hosts: Option<PathBuf>,
+	pub strict_host_key_checking: StrictHostKeyChecking,
+	pub insecure_accept_host_keys: bool,
+}
+
+impl SshTarget {
+	/// Options for the `ssh` command line that reproduce these connection
+	/// parameters, so that tools like rsync connect exactly like the client.
+	pub fn ssh_options(&self) -> Vec<String> {
+		let mut options = vec![
+			"-o".to_string(), format!("HostName={}", self.host_name),
+			"-p".to_string(), self.port.to_string(),
+			"-l".to_string(), self.user.clone(),
+		];
+		for identity_file in &self.identity_files {
+			options.push("-i".to_string());
+			options.push(identity_file.to_string_lossy().to_string());
+		}
+		if !self.proxy_jump.is_empty() {
+			options.push("-J".to_string());
+			options.push(self.proxy_jump.join(","));
+		}
+		if let Some(timeout) = self.connect_timeout {
+			options.push("-o".to_string());
+			options.push(format!("ConnectTimeout={}", timeout.as_secs()));
+		}
+		if self.insecure_accept_host_keys {
+			options.extend(["-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null"].map(String::from));
+		} else {
+			options.push("-o".to_string());
+			options.push(format!("StrictHostKeyChecking={}", self.strict_host_key_checking.as_ssh_option()));
+			if let Some(known_hosts) = &self.known_hosts {
+				options.push("-o".to_string());
+				options.push(format!("UserKnownHostsFile={}", known_hosts.to_string_lossy()));
+			}
+		}
+		options
+	}
+
+	/// The ssh command rsync connects with, logging in like `login` did.
+	fn remote_shell(&self, login: &Login) -> RemoteShell {
+		let mut options = self.ssh_options();
+		let askpass_secret = match login {
+			Login::Key { path, passphrase } => {
+				options.extend(["-i".to_string(), path.to_string_lossy().to_string(), "-o".to_string(), "IdentitiesOnly=yes".to_string()]);
+				passphrase.clone()
+			}
+			Login::Agent => None,
+			Login::Password(password) => {
+				options.extend(["-o", "PreferredAuthentications=password,keyboard-interactive"].map(String::from));
+				Some(password.clone())
+			}
+		};
+		// Without BatchMode ssh asks for the secret through SSH_ASKPASS
+		if askpass_secret.is_none() {
+			options.extend(["-o", "BatchMode=yes"].map(String::from));
+		}
+		let command = std::iter::once("ssh".to_string())
+			.chain(options.iter().map(|o| commands::shell_quote(o)))
+			.collect::<Vec<_>>()
+			.join(" ");
+		RemoteShell { command, askpass_secret }
+	}
 }
 
 impl NodeCommon {
-	pub fn new() -> Self{
+	pub fn new(insecure_accept_host_keys: bool, identity_files: Vec<PathBuf>, auth: HashMap<String, HostAuth>) -> Self{
 		Self{
 			username: env::var("USER").unwrap_or_else(|_| "root".to_string()),
-			ssh_config: get_ssh_config_cell()
+			ssh_config: SshConfig::load(),
+			insecure_accept_host_keys,
+			identity_files,
+			auth,
+			passphrases: Mutex::new(HashMap::new()),
+			prompt_lock: AsyncMutex::new(()),
+		}
+	}
+
+	/// The passphrase of the private key at `path`, asked for once if the key
+	/// is encrypted.
+	async fn key_passphrase(&self, path: &Path) -> Result<Option<String>> {
+		let _prompt = self.prompt_lock.lock().await;
+		if let Some(passphrase) = self.passphrases.lock().expect("passphrase mutex poisoned").get(path) {
+			return Ok(passphrase.clone());
+		}
+		let passphrase = match russh::keys::load_secret_key(path, None) {
+			Ok(_) => None,
+			Err(russh::keys::Error::KeyIsEncrypted) => Some(ask_passphrase(path)?),
+			Err(err) => return Err(err).with_context(|| format!("Failed to read key {}", path.display())),
+		};
+		self.passphrases.lock().expect("passphrase mutex poisoned").insert(path.to_path_buf(), passphrase.clone());
+		Ok(passphrase)
+	}
+
+	async fn ask_password(&self, target: &SshTarget) -> Result<String> {
+		let _prompt = self.prompt_lock.lock().await;
+		let prompt = format!("{}@{}'s password: ", target.user, target.alias);
+		tokio::task::block_in_place(|| rpassword::prompt_password(prompt))
+			.with_context(|| format!("Failed to read the password for {}", target.alias))
+	}
+
+	/// Resolves `hostname` through `~/.ssh/config` like ssh does.
+	pub fn resolve(&self, hostname: &str) -> SshTarget {
+		let options = self.ssh_config.as_ref().map(|c| c.query(hostname)).unwrap_or_default();
+		let host_name = options.get("HostName").unwrap_or(hostname).replace("%h", hostname);
+		let port = options.get("Port").and_then(|p| p.parse::<u16>().ok()).unwrap_or(22);
+		let user = options.get("User").unwrap_or(self.username.as_str()).to_string();
+		let host_auth = self.auth.get(hostname);
+		// Like ssh, keys from the command line come before the ones from ssh_config
+		let identity_files = host_auth.iter()
+			.flat_map(|a| a.identity_files.iter())
+			.map(|f| ssh_options::expand_path(f, &host_name, port, &user, &self.username))
+			.chain(self.identity_files.iter().cloned())
+			.chain(options.get_all("IdentityFile").iter()
+				.map(|f| ssh_options::expand_path(f, &host_name, port, &user, &self.username)))
+			.collect();
+		let auth_methods = host_auth.map(|a| a.methods.clone()).unwrap_or_else(config_file::default_auth_methods);
+		let proxy_jump = match options.get("ProxyJump") {
+			Some(jump) if !jump.eq_ignore_ascii_case("none") => jump.split(',').map(|j| j.trim().to_string()).collect(),
+			_ => Vec::new(),
+		};
+		let connect_timeout = options.get("ConnectTimeout")
+			.and_then(|t| t.parse::<u64>().ok())
+			.map(Duration::from_secs);
+		let known_hosts = match options.get("UserKnownHostsFile") {
+			Some(files) => files.split_whitespace().next()
+				.map(|f| ssh_options::expand_path(f, &host_name, port, &user, &self.username)),
+			None => host_keys::default_known_hosts_file(),
+		};
+		let strict_host_key_checking = options.get("StrictHostKeyChecking")
+			.map(StrictHostKeyChecking::parse)
+			.unwrap_or(StrictHostKeyChecking::Yes);
+
+		SshTarget {
+			alias: hostname.to_string(),
+			host_name,
+			port,
+			user,
+			identity_files,
+			auth_methods,
+			proxy_jump,
+			connect_timeout,
+			known_hosts,
+			strict_host_key_checking,
+			insecure_accept_host_keys: self.insecure_accept_host_keys,
+		}
+	}
+}
+
+/// Asks for the passphrase of the encrypted key at `path` until it decrypts
+/// the key, at most three times.
+fn ask_passphrase(path: &Path) -> Result<String> {
+	let prompt = format!("Enter passphrase for key '{}': ", path.display());
+	for _ in 0..3 {
+		let passphrase = tokio::task::block_in_place(|| rpassword::prompt_password(&prompt))
+			.with_context(|| format!("Failed to read the passphrase for {}", path.display()))?;
+		if russh::keys::load_secret_key(path, Some(&passphrase)).is_ok() {
+			return Ok(passphrase);
 		}
+		warn!("Bad passphrase for {}", path.display());
+	}
+	anyhow::bail!("No valid passphrase given for {}", path.display())
+}
+
+/// Runs `future`, failing after `timeout` if one is given.
+async fn within<T>(timeout: Option<Duration>, host: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+	match timeout {
+		Some(timeout) => tokio::time::timeout(timeout, future).await
+			.map_err(|_| anyhow::anyhow!("Timed out connecting to {} after {}s", host, timeout.as_secs()))?,
+		None => future.await,
+	}
+}
+
+/// A host and the socket addresses it is reached at. For hosts behind a
+/// ProxyJump the address is the local end of the tunnel.
+#[derive(Clone)]
+struct Endpoint {
+	host_name: String,
+	addresses: Vec<SocketAddr>,
+}
+
+impl ToSocketAddrsWithHostname for Endpoint {
+	fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+		Ok(self.addresses.clone())
+	}
+
+	fn hostname(&self) -> String {
+		self.host_name.clone()
+	}
+}
+
+/// Local listener that forwards every connection to a host through its jump
+/// hosts with `ssh -W`. The listener stops when the tunnel is dropped.
+struct Tunnel {
+	address: SocketAddr,
+	task: JoinHandle<()>,
+}
+
+impl Drop for Tunnel {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+/// The ssh destination of the ProxyJump entry `jump`, `[user@]host[:port]`.
+/// ssh only takes a port in the destination in its URI form.
+fn jump_destination(jump: &str) -> String {
+	if jump.starts_with("ssh://") {
+		return jump.to_string();
+	}
+	let host = jump.rsplit_once('@').map_or(jump, |(_, host)| host);
+	let has_port = match host.strip_prefix('[') {
+		Some(bracketed) => bracketed.contains("]:"),
+		// More than one colon is a bare IPv6 address
+		None => host.matches(':').count() == 1,
+	};
+	if has_port {
+		format!("ssh://{}", jump)
+	} else {
+		jump.to_string()
+	}
+}
+
+impl Tunnel {
+	async fn open(target: &SshTarget) -> Result<Self> {
+		let listener = TcpListener::bind("127.0.0.1:0").await.context("Failed to open local tunnel port")?;
+		let address = listener.local_addr()?;
+		let (last_jump, first_jumps) = target.proxy_jump.split_last().context("Tunnel without jump host")?;
+		let mut command = Command::new("ssh");
+		command.arg("-o").arg("BatchMode=yes");
+		if !first_jumps.is_empty() {
+			command.arg("-J").arg(first_jumps.join(","));
+		}
+		if let Some(timeout) = target.connect_timeout {
+			command.arg("-o").arg(format!("ConnectTimeout={}", timeout.as_secs()));
+		}
+		command.arg("-W").arg(format!("[{}]:{}", target.host_name, target.port)).arg(jump_destination(last_jump));
+		command.stdin(Stdio::piped()).stdout(Stdio::piped()).kill_on_drop(true);
+
+		let host = target.alias.clone();
+		let task = tokio::spawn(async move {
+			while let Ok((mut socket, _)) = listener.accept().await {
+				let child = command.spawn();
+				let host = host.clone();
+				tokio::spawn(async move {
+					let mut child = match child {
+						Ok(child) => child,
+						Err(err) => {
+							error!("Failed to start ssh tunnel to {}\n{}", host, err);
+							return;
+						}
+					};
+					let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+						return;
+					};
+					let mut stream = tokio::io::join(stdout, stdin);
+					let _ = tokio::io::copy_bidirectional(&mut socket, &mut stream).await;
+					let _ = child.kill().await;
+				});
+			}
+		});
+		Ok(Self { address, task })
 	}
 }
 
 pub struct Node {
 	// common: &'a NodeCommon,
 	pub hostname: String,
-	pub client: Client,
 	pub threads: usize,
+	/// Available memory in MiB when the node connected
+	pub memory: Option<u64>,
+	/// Replaced when the node reconnects; commands that are still running
+	/// keep the client they started on
+	client: Mutex<Arc<Client>>,
+	target: SshTarget,
+	login: Login,
+	scratch: Scratch,
+	tunnel: Option<Tunnel>,
 }
 
 impl Node {
-	pub async fn try_new(common: & NodeCommon, hostname: &str) -> Result<Self> {
-		let mut host_name = hostname.to_string();
-		let mut port = 22;
-		let mut user = common.username.as_str();
-
-		if let Some(ssh_config_cell) = &common.ssh_config {
-			ssh_config_cell.with_dependent(|_owner, ssh_config| {
-				let params = ssh_config.query(hostname);
-				if !params.is_empty() {
-					if let Some(host_name_cfg) = params.get("HostName"){
-						host_name = host_name_cfg.to_string();
-					}
-					if let Some(port_cfg) = params.get("Port"){
-						port = port_cfg.parse::<u16>().unwrap_or(22);
-					}
-					if let Some(username_cfg) = params.get("User"){
-						user = username_cfg;
-					}
-				}
-			});
-		}
-		let client = Client::connect(
-			(host_name, port),
-			&user,
-			AuthMethod::Agent,
-			ServerCheckMethod::NoCheck,
-		)
-			.await
-			.context("Failed to connect to host")?;
+	pub async fn try_new(common: & NodeCommon, hostname: &str, scratch: Scratch) -> Result<Self> {
+		let target = common.resolve(hostname);
+		let (client, login, tunnel) = Self::connect(common, &target).await?;
 
 		let nproc_output = client.execute("nproc").await.context("failed to query for threads")?.stdout;
-		let threads = nproc_output.trim().parse::<usize>().expect(format!("Failed to parse threads: {}", nproc_output.trim()).as_str());
+		let threads = nproc_output.trim().parse::<usize>().with_context(|| format!("Failed to parse threads: {}", nproc_output.trim()))?;
+		let memory = match client.execute(MEMINFO_COMMAND).await {
+			Ok(output) => available_memory(&output.stdout),
+			Err(err) => {
+				warn!("Failed to read /proc/meminfo of {}\n{}", hostname, err);
+				None
+			}
+		};
+		if memory.is_none() {
+			warn!("Available memory of {} is unknown, memory_per_task is not enforced on it", hostname);
+		}
 
 		let node = Self {
 			// common,
 			hostname: hostname.to_string(),
-			client,
-			threads};
+			threads,
+			memory,
+			client: Mutex::new(Arc::new(client)),
+			target,
+			login,
+			scratch,
+			tunnel,
+		};
 		Ok(node)
 	}
 
+	/// Connects and logs in. `ConnectTimeout` applies to every connection
+	/// attempt but not to the time spent typing passphrases.
+	async fn connect(common: &NodeCommon, target: &SshTarget) -> Result<(Client, Login, Option<Tunnel>)> {
+		let tunnel = if target.proxy_jump.is_empty() {
+			None
+		} else {
+			Some(Tunnel::open(target).await?)
+		};
+		let (endpoint, server_check) = Self::endpoint(target, tunnel.as_ref()).await?;
+
+		let mut last_error = None;
+		for method in &target.auth_methods {
+			let logins = match method {
+				AuthKind::Key => {
+					let mut logins = Vec::new();
+					for path in target.identity_files.iter().filter(|f| f.exists()) {
+						match common.key_passphrase(path).await {
+							Ok(passphrase) => logins.push(Login::Key { path: path.clone(), passphrase }),
+							Err(err) => warn!("Skipping key {}\n{:#}", path.display(), err),
+						}
+					}
+					logins
+				}
+				AuthKind::Agent if env::var_os("SSH_AUTH_SOCK").is_none() => {
+					debug!("SSH_AUTH_SOCK is not set, not using an agent for {}", target.alias);
+					Vec::new()
+				}
+				AuthKind::Agent => vec![Login::Agent],
+				AuthKind::Password => vec![Login::Password(common.ask_password(target).await?)],
+			};
+			for login in logins {
+				match Self::log_in(target, &endpoint, &login, &server_check).await {
+					Ok(client) => return Ok((client, login, tunnel)),
+					Err(err) => last_error = Some(err),
+				}
+			}
+		}
+		match last_error {
+			Some(err) => Err(err).context("Failed to connect to host"),
+			None => anyhow::bail!("No authentication method available for {}", target.alias),
+		}
+	}
+
+	/// Where `target` is reached, through `tunnel` if it has one, and how its
+	/// host key is checked.
+	async fn endpoint(target: &SshTarget, tunnel: Option<&Tunnel>) -> Result<(Endpoint, ServerCheckMethod)> {
+		let endpoint = Endpoint {
+			host_name: target.host_name.clone(),
+			addresses: match &tunnel {
+				Some(tunnel) => vec![tunnel.address],
+				None => ToSocketAddrs::to_socket_addrs(&(target.host_name.as_str(), target.port))
+					.with_context(|| format!("Failed to resolve {}", target.host_name))?
+					.collect(),
+			},
+		};
+		let address = *endpoint.addresses.first().with_context(|| format!("{} did not resolve to any address", target.host_name))?;
+
+		let server_check = if target.insecure_accept_host_keys {
+			warn!("Accepting any host key of {} without verification", target.alias);
+			ServerCheckMethod::NoCheck
+		} else {
+			let known_hosts = target.known_hosts.as_deref().context("Could not determine the known_hosts file")?;
+			within(target.connect_timeout, &target.alias,
+				host_keys::server_check_method(address, &target.host_name, target.port, known_hosts, target.strict_host_key_checking)).await?
+		};
+		Ok((endpoint, server_check))
+	}
+
+	async fn log_in(target: &SshTarget, endpoint: &Endpoint, login: &Login, server_check: &ServerCheckMethod) -> Result<Client> {
+		let connect = async {
+			Client::connect(endpoint.clone(), &target.user, login.auth_method(), server_check.clone()).await
+				.map_err(anyhow::Error::from)
+		};
+		within(target.connect_timeout, &target.alias, connect).await
+	}
+
+	fn client(&self) -> Arc<Client> {
+		self.client.lock().expect("client mutex poisoned").clone()
+	}
+
+	/// Turns errors of the SSH transport into [`Disconnected`].
+	fn lost(&self, err: async_ssh2_tokio::Error) -> anyhow::Error {
+		use async_ssh2_tokio::Error;
+		match err {
+			Error::SshError(_) | Error::SendError(_) | Error::IoError(_) | Error::ChannelSendError(_) | Error::CommandDidntExit =>
+				Disconnected { host: self.hostname.clone(), source: err }.into(),
+			err => err.into(),
+		}
+	}
+
+
 	fn rsync_from_folder (from: &str) -> String {
 		if !from.ends_with('/'){
 			format!("{}/", from)
@@ -95,16 +480,18 @@ impl Node {
 
 	pub async fn rsync_from(&self, from: &str, to: &str, delete_src: bool) -> Result<()> {
 		let from = Self::rsync_from_folder(from);
-		commands::rsync(&format!("{}:{}", self.hostname, from), to, delete_src).await
+		commands::rsync(&format!("{}:{}", self.hostname, from), to, delete_src, Some(&self.target.remote_shell(&self.login))).await
 	}
 
 	pub async fn rsync_to(&self, from: &str, to: &str, delete_src: bool) -> Result<()> {
 		let from = Self::rsync_from_folder(from);
-		commands::rsync(from.as_str(), &format!("{}:{}", self.hostname, to), delete_src).await
+		commands::rsync(from.as_str(), &format!("{}:{}", self.hostname, to), delete_src, Some(&self.target.remote_shell(&self.login))).await
 	}
 
 	pub async fn rm(&self, dir: &str) -> Result<()> {
-		let output = self.client.execute(format!("rm -rf {dir}").as_str()).await.context("Failed to execute rm")?;
+		let output = self.client().execute(format!("rm -rf {}", commands::shell_quote(dir)).as_str()).await
+			.map_err(|err| self.lost(err))
+			.context("Failed to execute rm")?;
 		if output.exit_status != 0 {
 			return Err(anyhow::anyhow!("rm failed: {}", output.stderr));
 		}
@@ -112,6 +499,18 @@ impl Node {
 		Ok(())
 	}
 
+	/// Kills the processes of `job`.
+	pub async fn kill(&self, job: &JobRun<'_>) -> Result<()> {
+		let output = self.client().execute(self.scratch.kill_command(job).as_str()).await
+			.map_err(|err| self.lost(err))
+			.context("Failed to execute kill")?;
+		if output.exit_status != 0 {
+			return Err(anyhow::anyhow!("kill failed: {}", output.stderr));
+		}
+
+		Ok(())
+	}
+
 	// pub async fn cd(&self, dir: &str) -> Result<()> {
 	// 	let output = self.client.execute(format!("cd {dir}").as_str()).await.context("Failed to execute cd")?;
 	// 	if output.exit_status != 0 {
@@ -124,3 +523,110 @@ impl Node {
 
 
 }
+
+#[async_trait]
+impl Backend for Node {
+	fn name(&self) -> &str {
+		&self.hostname
+	}
+
+	fn threads(&self) -> usize {
+		self.threads
+	}
+
+	fn memory(&self) -> Option<u64> {
+		self.memory
+	}
+
+	async fn execute(&self, command: &str) -> Result<JobOutput> {
+		let output = self.client().execute(command).await.map_err(|err| self.lost(err))?;
+		Ok(JobOutput {
+			exit_status: output.exit_status,
+			stdout: output.stdout,
+			stderr: output.stderr,
+		})
+	}
+
+	async fn prepare_workdir(&self, workdir: &str) -> Result<()> {
+		self.rsync_to(workdir, &self.scratch.workdir(), false).await
+	}
+
+	async fn run_job(&self, job: &JobRun<'_>) -> Result<JobOutcome> {
+		if job.detached {
+			return detached::run(self, &self.scratch, job).await;
+		}
+		// sshd makes the command a session leader, as kill_command expects
+		let command = self.scratch.job_command(job);
+		let client = self.client();
+		let execution = match job.timeout {
+			Some(timeout) => tokio::time::timeout(timeout, client.execute(command.as_str())).await.ok(),
+			None => Some(client.execute(command.as_str()).await),
+		};
+		match execution {
+			Some(output) => {
+				let output = output.map_err(|err| self.lost(err))?;
+				Ok(JobOutcome::Exited(JobOutput {
+					exit_status: output.exit_status,
+					stdout: output.stdout,
+					stderr: output.stderr,
+				}))
+			}
+			None => {
+				if let Err(err) = self.kill(job).await {
+					error!("failed to kill task {} on {}\n{}", job.id, self.hostname, err);
+				}
+				Ok(JobOutcome::TimedOut)
+			}
+		}
+	}
+
+	async fn reattach(&self, job: &JobRun<'_>) -> Result<Option<JobOutcome>> {
+		detached::reattach(self, &self.scratch, job).await
+	}
+
+	async fn is_running(&self, id: &str) -> Result<bool> {
+		detached::is_running(self, &self.scratch, id).await
+	}
+
+	async fn kill_job(&self, job: &JobRun<'_>) -> Result<()> {
+		self.kill(job).await
+	}
+
+	async fn fetch_results(&self, id: &str, destination: &Path) -> Result<()> {
+		let destination = destination.to_str().context("result path is not valid UTF-8")?;
+		self.rsync_from(&self.scratch.result_dir(id), destination, true).await
+	}
+
+	async fn cleanup(&self) -> Result<()> {
+		self.rm(self.scratch.root()).await
+	}
+
+	/// Logs in again the way the node first did, without asking for secrets
+	async fn reconnect(&self) -> Result<()> {
+		let (endpoint, server_check) = Self::endpoint(&self.target, self.tunnel.as_ref()).await?;
+		let client = Self::log_in(&self.target, &endpoint, &self.login, &server_check).await?;
+		*self.client.lock().expect("client mutex poisoned") = Arc::new(client);
+		info!("Reconnected to {}", self.hostname);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::jump_destination;
+
+	#[test]
+	fn jump_hosts_with_ports_become_uris() {
+		assert_eq!(jump_destination("bastion:2222"), "ssh://bastion:2222");
+		assert_eq!(jump_destination("me@bastion:2222"), "ssh://me@bastion:2222");
+		assert_eq!(jump_destination("[fe80::1]:2222"), "ssh://[fe80::1]:2222");
+	}
+
+	#[test]
+	fn jump_hosts_without_ports_are_kept() {
+		assert_eq!(jump_destination("bastion"), "bastion");
+		assert_eq!(jump_destination("me@bastion"), "me@bastion");
+		assert_eq!(jump_destination("fe80::1"), "fe80::1");
+		assert_eq!(jump_destination("ssh://me@bastion:2222"), "ssh://me@bastion:2222");
+	}
+}
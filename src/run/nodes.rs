@@ -1,7 +1,11 @@
+use crate::run::backend::{Backend, Scratch};
+use crate::run::config_file::SlurmConfig;
+use crate::run::local::LocalNode;
 use crate::run::node::{Node, NodeCommon};
-use futures::future::{try_join_all};
-use futures::TryFutureExt;
+use crate::run::slurm::SlurmBackend;
+use futures::future::{join_all};
 use anyhow::{Result};
+use spdlog::prelude::*;
 
 
 // #[derive(Debug)]
@@ -14,30 +18,58 @@ use anyhow::{Result};
 // }
 
 pub struct Nodes {
-	common: NodeCommon,
-	pub nodes: Vec<Node>,
+	pub nodes: Vec<Box<dyn Backend>>,
 }
 
 impl Nodes {
-	pub async fn new(nodes_hostnames: &Vec<String>) -> Result<Self> {
-		let mut nodes = Nodes{
-			common: NodeCommon::new(),
-			nodes : Vec::with_capacity(nodes_hostnames.len()),
-		};
-		let common_ref = &nodes.common;
+	/// Connects to every host. Hosts that cannot be reached are reported and
+	/// left out; an error is returned only if fewer than `min_nodes` remain.
+	pub async fn new(nodes_hostnames: &[String], min_nodes: usize, common: NodeCommon, scratch: &Scratch) -> Result<Self> {
+		let mut nodes = Vec::with_capacity(nodes_hostnames.len());
+		let common_ref = &common;
 		let node_futures = nodes_hostnames.iter().map(move |hostname| {
-			Node::try_new(common_ref, hostname)
-				.map_err(move |e| {
-					e.context(format!("Failed to create node for hostname '{}'", hostname))
-				})
-				// .map_err(move |e| Error::NodeCreation {
-				// 	hostname: hostname.to_string(),
-				// 	source: e,
-				// })
-
+			Node::try_new(common_ref, hostname, scratch.clone())
 		});
 
-		nodes.nodes.extend(try_join_all(node_futures).await?);
-		Ok(nodes)
+		let mut unreachable = Vec::new();
+		for (hostname, result) in nodes_hostnames.iter().zip(join_all(node_futures).await) {
+			match result {
+				Ok(node) => nodes.push(Box::new(node) as Box<dyn Backend>),
+				Err(err) => {
+					error!("Failed to create node for hostname '{}', it will be skipped\n{:#}", hostname, err);
+					unreachable.push(hostname.as_str());
+				}
+			}
+		}
+
+		if nodes.len() < min_nodes {
+			anyhow::bail!("Only {} of {} nodes are reachable, at least {} required (unreachable: {})",
+				nodes.len(), nodes_hostnames.len(), min_nodes, unreachable.join(", "));
+		}
+		if !unreachable.is_empty() {
+			warn!("Continuing with {} of {} nodes, unreachable: {}", nodes.len(), nodes_hostnames.len(), unreachable.join(", "));
+		}
+		Ok(Nodes { nodes })
+	}
+
+	/// The single node of the local backend.
+	pub fn local(scratch: &Scratch) -> Result<Self> {
+		Ok(Nodes {
+			nodes: vec![Box::new(LocalNode::new(scratch.clone())?)],
+		})
 	}
-}
\ No newline at end of file
+
+	/// The Slurm backend, submitting from `config.login_host` or this machine.
+	pub async fn slurm(config: SlurmConfig, common: NodeCommon, scratch: &Scratch, max_job_threads: usize) -> Result<Self> {
+		if !config.scratch_dir.starts_with('/') {
+			anyhow::bail!("slurm.scratch_dir must be an absolute path, got {}", config.scratch_dir);
+		}
+		let login: Box<dyn Backend> = match &config.login_host {
+			Some(host) => Box::new(Node::try_new(&common, host, scratch.clone()).await?),
+			None => Box::new(LocalNode::new(scratch.clone())?),
+		};
+		Ok(Nodes {
+			nodes: vec![Box::new(SlurmBackend::new(login, scratch.clone(), config, max_job_threads))],
+		})
+	}
+}
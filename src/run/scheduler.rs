@@ -0,0 +1,298 @@
+//! Work-stealing job scheduling.
+//!
+//! Every node has a deque of its own, filled at the start with jobs in order
+//! of decreasing estimated cost so that long jobs start first. A node that
+//! runs out of work takes jobs from the shared injector, which holds retries,
+//! and then steals from the other nodes. Costs are estimated from the
+//! durations that earlier runs recorded in their result folders.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use spdlog::prelude::*;
+use crate::run::config_file::{Config, Permutation};
+use crate::run::job::Job;
+
+/// File in a job result folder holding how long the job ran, in seconds
+pub const DURATION_FILE: &str = "duration";
+
+/// Records in the result folder `dir` that its job ran for `duration`.
+pub fn record_duration(dir: &Path, duration: Duration) {
+	if let Err(err) = fs::write(dir.join(DURATION_FILE), format!("{:.3}\n", duration.as_secs_f64())) {
+		warn!("Failed to record the duration of {}\n{}", dir.display(), err);
+	}
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+	(!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Durations of earlier runs, used to estimate the cost of jobs.
+#[derive(Default)]
+pub struct CostModel {
+	by_id: HashMap<String, f64>,
+	by_arguments: HashMap<Vec<(String, String)>, Vec<f64>>,
+	by_value: HashMap<(String, String), Vec<f64>>,
+	all: Vec<f64>,
+}
+
+impl CostModel {
+	/// Reads the recorded durations of the result folders in `results_path`.
+	pub fn load(results_path: &Path, config: &Config) -> Self {
+		let mut model = Self::default();
+		let Ok(entries) = fs::read_dir(results_path) else {
+			return model;
+		};
+		let permutations = config.get_arguments_permutations();
+		for entry in entries.flatten() {
+			let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+				continue;
+			};
+			let Some(seconds) = fs::read_to_string(entry.path().join(DURATION_FILE)).ok()
+				.and_then(|s| s.trim().parse::<f64>().ok()) else {
+				continue;
+			};
+			let arguments = match permutations.get(&id) {
+				Some(permutation) => permutation.arguments.clone(),
+				None => match config.parse_permutation_id(&id) {
+					Some((arguments, _)) => arguments,
+					None => continue,
+				},
+			};
+			for argument in &arguments {
+				model.by_value.entry(argument.clone()).or_default().push(seconds);
+			}
+			model.by_arguments.entry(arguments).or_default().push(seconds);
+			model.by_id.insert(id, seconds);
+			model.all.push(seconds);
+		}
+		model
+	}
+
+	/// Estimated run time of `permutation` in seconds. Falls back from the
+	/// same job to other repeats of it, to jobs sharing argument values and
+	/// finally to the mean of all jobs.
+	pub fn estimate(&self, permutation: &Permutation) -> f64 {
+		if let Some(seconds) = self.by_id.get(&permutation.id) {
+			return *seconds;
+		}
+		if let Some(seconds) = self.by_arguments.get(&permutation.arguments).and_then(|d| mean(d)) {
+			return seconds;
+		}
+		let value_means: Vec<f64> = permutation.arguments.iter()
+			.filter_map(|argument| self.by_value.get(argument).and_then(|d| mean(d)))
+			.collect();
+		mean(&value_means).or_else(|| mean(&self.all)).unwrap_or(0.0)
+	}
+}
+
+pub struct Scheduler {
+	/// The deque of each node. crossbeam's `Worker` is not `Sync`, but the
+	/// workers of a node share it, so it is locked for every pop.
+	locals: Vec<Mutex<Worker<Job>>>,
+	stealers: Vec<Stealer<Job>>,
+	injector: Injector<Job>,
+	/// Jobs that have not finished for good, including running ones
+	pending: AtomicUsize,
+}
+
+impl Scheduler {
+	/// Deals `jobs` out to nodes that run `slots[i]` jobs at a time. Jobs are
+	/// taken longest first and each goes to the node that would finish its
+	/// share earliest, so every deque is ordered by decreasing cost.
+	pub fn new(mut jobs: Vec<Job>, costs: &CostModel, slots: &[usize]) -> Self {
+		let locals: Vec<Worker<Job>> = slots.iter().map(|_| Worker::new_fifo()).collect();
+		let stealers = locals.iter().map(Worker::stealer).collect();
+		let pending = AtomicUsize::new(jobs.len());
+
+		let mut estimated: Vec<(f64, Job)> = jobs.drain(..).map(|job| (costs.estimate(&job.permutation), job)).collect();
+		estimated.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.permutation.id.cmp(&b.1.permutation.id)));
+		let mut load = vec![0.0; slots.len()];
+		let injector = Injector::new();
+		for (cost, job) in estimated {
+			let Some(node) = (0..slots.len()).min_by(|&a, &b| {
+				let finish = |i: usize| (load[i] + cost.max(1.0)) / slots[i].max(1) as f64;
+				finish(a).total_cmp(&finish(b))
+			}) else {
+				injector.push(job);
+				continue;
+			};
+			load[node] += cost.max(1.0);
+			locals[node].push(job);
+		}
+
+		Self {
+			locals: locals.into_iter().map(Mutex::new).collect(),
+			stealers,
+			injector,
+			pending,
+		}
+	}
+
+	/// The next job for `node`: its own, then a shared one, then one stolen
+	/// from another node.
+	pub fn pop(&self, node: usize) -> Option<Job> {
+		let local = self.locals[node].lock().expect("deque mutex poisoned");
+		if let Some(job) = local.pop() {
+			return Some(job);
+		}
+		loop {
+			let mut retry = false;
+			match self.injector.steal_batch_and_pop(&local) {
+				Steal::Success(job) => return Some(job),
+				Steal::Retry => retry = true,
+				Steal::Empty => {}
+			}
+			let count = self.stealers.len();
+			for victim in (1..count).map(|offset| (node + offset) % count) {
+				match self.stealers[victim].steal() {
+					Steal::Success(job) => {
+						trace!("Node {} stole {} from node {}", node, job.permutation.id, victim);
+						return Some(job);
+					}
+					Steal::Retry => retry = true,
+					Steal::Empty => {}
+				}
+			}
+			if !retry {
+				return None;
+			}
+		}
+	}
+
+	/// Puts a job back to be picked up by any node, e.g. for a retry.
+	pub fn push(&self, job: Job) {
+		self.injector.push(job);
+	}
+
+	/// Marks a job that was popped as done for good.
+	pub fn finish(&self) {
+		self.pending.fetch_sub(1, Ordering::Release);
+	}
+
+	pub fn is_done(&self) -> bool {
+		self.pending.load(Ordering::Acquire) == 0
+	}
+
+	/// Takes out every job that is still queued.
+	pub fn drain(&self) -> Vec<Job> {
+		let mut jobs = Vec::new();
+		loop {
+			match self.injector.steal() {
+				Steal::Success(job) => jobs.push(job),
+				Steal::Retry => continue,
+				Steal::Empty => break,
+			}
+		}
+		for local in &self.locals {
+			let local = local.lock().expect("deque mutex poisoned");
+			while let Some(job) = local.pop() {
+				jobs.push(job);
+			}
+		}
+		jobs
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn job(a: &str, repeat: usize) -> Job {
+		let permutation = Permutation {
+			id: format!("a={}_{}", a, repeat),
+			argv: vec![format!("--a={}", a)],
+			arguments: vec![("a".to_string(), a.to_string())],
+			repeat,
+		};
+		Job::new(permutation, 1, 0)
+	}
+
+	/// A model that knows how long `a=<value>` jobs took.
+	fn costs(durations: &[(&str, f64)]) -> CostModel {
+		let mut model = CostModel::default();
+		for (value, seconds) in durations {
+			let argument = ("a".to_string(), value.to_string());
+			model.by_id.insert(format!("a={}_0", value), *seconds);
+			model.by_arguments.entry(vec![argument.clone()]).or_default().push(*seconds);
+			model.by_value.entry(argument).or_default().push(*seconds);
+			model.all.push(*seconds);
+		}
+		model
+	}
+
+	fn ids(scheduler: &Scheduler, node: usize) -> Vec<String> {
+		std::iter::from_fn(|| scheduler.pop(node)).map(|job| job.permutation.id).collect()
+	}
+
+	#[test]
+	fn estimates_fall_back_from_id_to_arguments_to_mean() {
+		let model = costs(&[("1", 10.0), ("2", 30.0)]);
+		assert_eq!(model.estimate(&job("1", 0).permutation), 10.0);
+		assert_eq!(model.estimate(&job("2", 1).permutation), 30.0);
+		assert_eq!(model.estimate(&job("3", 0).permutation), 20.0);
+		assert_eq!(CostModel::default().estimate(&job("1", 0).permutation), 0.0);
+	}
+
+	#[test]
+	fn longest_jobs_start_first() {
+		let model = costs(&[("1", 1.0), ("2", 50.0), ("3", 10.0)]);
+		let jobs = ["1", "2", "3"].iter().map(|a| job(a, 0)).collect();
+		let scheduler = Scheduler::new(jobs, &model, &[1]);
+		assert_eq!(ids(&scheduler, 0), ["a=2_0", "a=3_0", "a=1_0"]);
+	}
+
+	#[test]
+	fn jobs_are_balanced_by_slots() {
+		let model = costs(&[("1", 40.0), ("2", 30.0), ("3", 20.0), ("4", 10.0)]);
+		let jobs = ["1", "2", "3", "4"].iter().map(|a| job(a, 0)).collect();
+		let scheduler = Scheduler::new(jobs, &model, &[1, 3]);
+		// Node 1 runs three jobs at a time, so it gets a share three times as large
+		let local = |node: usize| -> Vec<String> {
+			let local = scheduler.locals[node].lock().unwrap();
+			std::iter::from_fn(|| local.pop()).map(|job| job.permutation.id).collect()
+		};
+		assert_eq!(local(0), ["a=3_0"]);
+		assert_eq!(local(1), ["a=1_0", "a=2_0", "a=4_0"]);
+	}
+
+	#[test]
+	fn idle_nodes_steal_from_others() {
+		let jobs = (0..4).map(|repeat| job("1", repeat)).collect();
+		let scheduler = Scheduler::new(jobs, &CostModel::default(), &[1, 1]);
+		let mut taken = ids(&scheduler, 0);
+		assert_eq!(taken.len(), 4);
+		assert!(scheduler.pop(1).is_none());
+		taken.sort();
+		assert_eq!(taken, ["a=1_0", "a=1_1", "a=1_2", "a=1_3"]);
+	}
+
+	#[test]
+	fn pushed_jobs_go_to_any_node() {
+		let scheduler = Scheduler::new(vec![], &CostModel::default(), &[1, 1]);
+		scheduler.push(job("1", 0));
+		assert_eq!(ids(&scheduler, 1), ["a=1_0"]);
+	}
+
+	#[test]
+	fn pending_counts_jobs_until_finished() {
+		let jobs = (0..2).map(|repeat| job("1", repeat)).collect();
+		let scheduler = Scheduler::new(jobs, &CostModel::default(), &[1]);
+		let first = scheduler.pop(0).unwrap();
+		assert!(!scheduler.is_done());
+		scheduler.finish();
+		// A retry is pushed back without finishing
+		scheduler.push(first);
+		assert!(!scheduler.is_done());
+		let mut left: Vec<String> = scheduler.drain().into_iter().map(|job| job.permutation.id).collect();
+		left.sort();
+		assert_eq!(left, ["a=1_0", "a=1_1"]);
+		assert!(scheduler.pop(0).is_none());
+		scheduler.finish();
+		assert!(scheduler.is_done());
+	}
+}
@@ -0,0 +1,57 @@
+//! Handling of SIGINT and SIGTERM during a run.
+//!
+//! The first signal stops the dispatch of new jobs and lets the running ones
+//! finish. The second one kills the running jobs, after which the nodes are
+//! cleaned up as usual. A third signal exits right away.
+
+use anyhow::{Context, Result};
+use spdlog::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+pub struct Shutdown {
+	/// Number of signals received so far
+	signals: watch::Receiver<usize>,
+}
+
+impl Shutdown {
+	/// Starts counting the signals received by the process.
+	pub fn listen() -> Result<Self> {
+		let mut interrupt = signal(SignalKind::interrupt()).context("failed to handle SIGINT")?;
+		let mut terminate = signal(SignalKind::terminate()).context("failed to handle SIGTERM")?;
+		let (sender, signals) = watch::channel(0);
+		tokio::spawn(async move {
+			loop {
+				tokio::select! {
+					_ = interrupt.recv() => {}
+					_ = terminate.recv() => {}
+				}
+				let count = *sender.borrow() + 1;
+				match count {
+					1 => warn!("Stopping: no new jobs are started and running ones are waited for. Interrupt again to kill them"),
+					2 => warn!("Killing running jobs and cleaning up. Interrupt again to exit right away"),
+					_ => {
+						error!("Exiting without cleaning up");
+						std::process::exit(130);
+					}
+				}
+				sender.send_replace(count);
+			}
+		});
+		Ok(Self { signals })
+	}
+
+	/// Whether new jobs should no longer be started
+	pub fn is_draining(&self) -> bool {
+		*self.signals.borrow() >= 1
+	}
+
+	/// Completes once running jobs should be killed.
+	pub async fn aborted(&self) {
+		let mut signals = self.signals.clone();
+		if signals.wait_for(|count| *count >= 2).await.is_err() {
+			// The listener never stops, but without it there is nothing to wait for
+			std::future::pending::<()>().await;
+		}
+	}
+}
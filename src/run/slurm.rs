@@ -0,0 +1,420 @@
+//! Backend that runs jobs as Slurm array jobs.
+//!
+//! Jobs handed in within [`SUBMIT_DELAY`] of each other and with the same
+//! timeout are submitted together as one `sbatch --array`. Every array task
+//! looks up its job id in a list file next to the array script and runs the
+//! job's `run.sh` on a compute node, recording its stdout, stderr and exit
+//! status in the job directory. The scratch directory therefore has to be on
+//! a filesystem shared by the login and the compute nodes.
+//!
+//! The state of the tasks is polled with `squeue`; tasks that left the queue
+//! are looked up with `sacct` to tell timeouts and cancellations apart.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use spdlog::prelude::*;
+use tokio::sync::Mutex as AsyncMutex;
+use super::backend::{execute_reconnecting, Backend, JobOutcome, JobOutput, JobRun, Scratch};
+use super::commands::shell_quote;
+use super::config_file::SlurmConfig;
+
+/// Time a job waits for further jobs to share its array before submission
+const SUBMIT_DELAY: Duration = Duration::from_secs(1);
+/// Polls after which a task that neither squeue nor sacct know is given up
+const MAX_UNKNOWN_POLLS: usize = 30;
+/// File in a job directory naming the array task that runs the job
+const TASK_FILE: &str = "slurm_task";
+/// States in which a task has not finished yet
+const ACTIVE_STATES: &[&str] = &["PENDING", "CONFIGURING", "RUNNING", "COMPLETING", "SUSPENDED", "REQUEUED", "REQUEUE_HOLD", "REQUEUE_FED", "RESIZING", "SIGNALING", "STAGE_OUT", "STOPPED"];
+const POLL_SEPARATOR: &str = "--- sacct ---";
+
+/// What the tasks of an array job request. Jobs are only put in the same
+/// array if they request the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TaskResources {
+	timeout: Option<Duration>,
+	threads: usize,
+	/// Memory in MiB
+	memory: Option<u64>,
+}
+
+/// A task of a submitted array job.
+#[derive(Debug, Clone)]
+struct ArrayTask {
+	job_id: String,
+	index: usize,
+}
+
+/// States of the tasks of an array job as seen by the last poll.
+struct Snapshot {
+	polled: Instant,
+	/// Tasks listed by squeue
+	queued: HashMap<usize, String>,
+	/// Tasks listed by sacct
+	accounted: HashMap<usize, String>,
+}
+
+impl Snapshot {
+	fn state(&self, index: usize) -> Option<&str> {
+		self.queued.get(&index).or_else(|| self.accounted.get(&index)).map(String::as_str)
+	}
+}
+
+pub struct SlurmBackend {
+	name: String,
+	/// Where sbatch, squeue and sacct run and files are copied to
+	login: Box<dyn Backend>,
+	scratch: Scratch,
+	config: SlurmConfig,
+	/// Threads of the largest job, which sizes the share of the cluster in use
+	max_job_threads: usize,
+	/// Jobs waiting to be submitted, with the resources they request
+	pending: Mutex<Vec<(String, TaskResources)>>,
+	/// Array tasks of submitted jobs, or why their submission failed
+	submitted: Mutex<HashMap<String, Result<ArrayTask, String>>>,
+	submit_lock: AsyncMutex<()>,
+	/// Array tasks of the jobs that are being waited for
+	running: Mutex<HashMap<String, ArrayTask>>,
+	array_count: AtomicUsize,
+	snapshots: AsyncMutex<HashMap<String, Snapshot>>,
+}
+
+impl SlurmBackend {
+	pub fn new(login: Box<dyn Backend>, scratch: Scratch, config: SlurmConfig, max_job_threads: usize) -> Self {
+		Self {
+			name: format!("slurm@{}", login.name()),
+			login,
+			scratch,
+			config,
+			max_job_threads,
+			pending: Mutex::new(Vec::new()),
+			submitted: Mutex::new(HashMap::new()),
+			submit_lock: AsyncMutex::new(()),
+			running: Mutex::new(HashMap::new()),
+			array_count: AtomicUsize::new(0),
+			snapshots: AsyncMutex::new(HashMap::new()),
+		}
+	}
+
+	fn poll_interval(&self) -> Duration {
+		Duration::from_secs(self.config.poll_interval)
+	}
+
+	/// Runs `command` on the login node, failing if it exits with an error.
+	async fn run(&self, command: &str) -> Result<String> {
+		let output = self.login.execute(command).await?;
+		if output.exit_status != 0 {
+			anyhow::bail!("`{}` exited with status {}: {}", command, output.exit_status, output.stderr.trim());
+		}
+		Ok(output.stdout)
+	}
+
+	async fn read_file(&self, path: &str) -> Result<Option<String>> {
+		let output = execute_reconnecting(self.login.as_ref(), &format!("cat {} 2>/dev/null", shell_quote(path))).await?;
+		Ok((output.exit_status == 0).then_some(output.stdout))
+	}
+
+	/// Writes the script of `job` and queues it for submission.
+	async fn stage(&self, job: &JobRun<'_>) -> Result<()> {
+		let job_dir = shell_quote(&self.scratch.job_dir(job.id));
+		self.run(&format!("mkdir -p {job_dir} && cd {job_dir} && rm -f stdout stderr exit_status {TASK_FILE} && printf '%s\\n' {} > run.sh",
+			shell_quote(&self.scratch.job_command(job)))).await?;
+		self.pending.lock().expect("pending mutex poisoned").push((job.id.to_string(), TaskResources {
+			timeout: job.timeout,
+			threads: job.threads,
+			memory: job.memory,
+		}));
+		Ok(())
+	}
+
+	/// Submits everything pending unless another job already did, and returns
+	/// the array task of job `id`.
+	async fn submit(&self, id: &str) -> Result<ArrayTask> {
+		tokio::time::sleep(SUBMIT_DELAY).await;
+		let _submit = self.submit_lock.lock().await;
+		if let Some(task) = self.submitted.lock().expect("submitted mutex poisoned").remove(id) {
+			return task.map_err(anyhow::Error::msg);
+		}
+
+		let pending = std::mem::take(&mut *self.pending.lock().expect("pending mutex poisoned"));
+		let mut arrays: HashMap<TaskResources, Vec<String>> = HashMap::new();
+		for (id, resources) in pending {
+			arrays.entry(resources).or_default().push(id);
+		}
+		for (resources, ids) in arrays {
+			let result = self.submit_array(&ids, resources).await;
+			if let Err(err) = &result {
+				error!("Failed to submit {} jobs to Slurm\n{:#}", ids.len(), err);
+			}
+			let mut submitted = self.submitted.lock().expect("submitted mutex poisoned");
+			for (index, id) in ids.into_iter().enumerate() {
+				let task = match &result {
+					Ok(job_id) => Ok(ArrayTask { job_id: job_id.clone(), index }),
+					Err(err) => Err(format!("{:#}", err)),
+				};
+				submitted.insert(id, task);
+			}
+		}
+
+		let task = self.submitted.lock().expect("submitted mutex poisoned").remove(id);
+		task.with_context(|| format!("Job {} was not submitted", id))?.map_err(anyhow::Error::msg)
+	}
+
+	/// Submits the jobs `ids` as one array job of tasks requesting
+	/// `resources` and returns its job id.
+	async fn submit_array(&self, ids: &[String], resources: TaskResources) -> Result<String> {
+		let jobs_dir = self.scratch.jobs_dir();
+		let array = format!("array-{}", self.array_count.fetch_add(1, Ordering::Relaxed));
+		let ids_file = format!("{}/{}.ids", jobs_dir, array);
+		let script_file = format!("{}/{}.sh", jobs_dir, array);
+		let script = format!("#!/bin/sh\n\
+			id=$(sed -n \"$((SLURM_ARRAY_TASK_ID + 1))p\" {})\n\
+			dir={}/\"$id\"\n\
+			sh \"$dir/run.sh\" > \"$dir/stdout\" 2> \"$dir/stderr\"\n\
+			echo $? > \"$dir/exit_status\"\n",
+			shell_quote(&ids_file), shell_quote(&jobs_dir));
+		self.run(&format!("printf '%s\\n' {} > {} && printf '%s' {} > {}",
+			shell_quote(&ids.join("\n")), shell_quote(&ids_file),
+			shell_quote(&script), shell_quote(&script_file))).await?;
+
+		let mut sbatch = vec![
+			"sbatch".to_string(),
+			"--parsable".to_string(),
+			"--job-name=MNER".to_string(),
+			format!("--array=0-{}", ids.len() - 1),
+			"--output=/dev/null".to_string(),
+		];
+		// threads_per_task = 0 asks for a node of its own
+		match resources.threads {
+			0 => sbatch.push("--exclusive".to_string()),
+			threads => sbatch.push(format!("--cpus-per-task={}", threads)),
+		}
+		if let Some(memory) = resources.memory {
+			sbatch.push(format!("--mem={}M", memory));
+		}
+		if let Some(timeout) = resources.timeout {
+			let seconds = timeout.as_secs().max(1);
+			sbatch.push(format!("--time={}:{:02}", seconds / 60, seconds % 60));
+		}
+		if let Some(partition) = &self.config.partition {
+			sbatch.push(format!("--partition={}", partition));
+		}
+		sbatch.extend(self.config.sbatch_args.iter().cloned());
+		sbatch.push(script_file);
+		let command = sbatch.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ");
+
+		let stdout = self.run(&format!("cd {} && {}", shell_quote(&jobs_dir), command)).await?;
+		// --parsable prints `<job id>` or `<job id>;<cluster>`
+		let job_id = stdout.trim().split(';').next().unwrap_or_default().to_string();
+		if job_id.is_empty() || !job_id.chars().all(|c| c.is_ascii_digit()) {
+			anyhow::bail!("Unexpected sbatch output: {}", stdout.trim());
+		}
+		info!("Submitted {} jobs as Slurm array job {}", ids.len(), job_id);
+		// Lets a later controller find the tasks again
+		let task_files = ids.iter().enumerate()
+			.map(|(index, id)| format!("printf '%s' {}_{} > {}", job_id, index, shell_quote(&format!("{}/{}", self.scratch.job_dir(id), TASK_FILE))))
+			.collect::<Vec<_>>()
+			.join(" && ");
+		if let Err(err) = self.run(&task_files).await {
+			warn!("Failed to record the tasks of Slurm array job {}\n{:#}", job_id, err);
+		}
+		Ok(job_id)
+	}
+
+	/// The state of `task`, polling Slurm if the last poll of its array job
+	/// is older than the poll interval.
+	async fn task_state(&self, task: &ArrayTask) -> Result<(bool, Option<String>)> {
+		let mut snapshots = self.snapshots.lock().await;
+		let fresh = snapshots.get(&task.job_id).is_some_and(|s| s.polled.elapsed() < self.poll_interval());
+		if !fresh {
+			let job_id = shell_quote(&task.job_id);
+			let output = execute_reconnecting(self.login.as_ref(), &format!("squeue -h -r -j {job_id} -o '%i %T' 2>/dev/null; echo {}; sacct -n -P -X -j {job_id} -o JobID,State",
+				shell_quote(POLL_SEPARATOR))).await?;
+			let (queued, accounted) = output.stdout.split_once(POLL_SEPARATOR).unwrap_or((&output.stdout, ""));
+			snapshots.insert(task.job_id.clone(), Snapshot {
+				polled: Instant::now(),
+				queued: parse_states(queued, ' '),
+				accounted: parse_states(accounted, '|'),
+			});
+		}
+		let snapshot = &snapshots[&task.job_id];
+		let state = snapshot.state(task.index).map(str::to_string);
+		let active = snapshot.queued.contains_key(&task.index)
+			|| state.as_deref().is_some_and(|s| ACTIVE_STATES.contains(&s));
+		Ok((active, state))
+	}
+
+	/// The array task recorded for job `id` when it was submitted.
+	async fn recorded_task(&self, id: &str) -> Result<Option<ArrayTask>> {
+		Ok(self.read_file(&format!("{}/{}", self.scratch.job_dir(id), TASK_FILE)).await?
+			.and_then(|task| {
+				let (job_id, index) = task.trim().split_once('_')?;
+				Some(ArrayTask { job_id: job_id.to_string(), index: index.parse().ok()? })
+			}))
+	}
+
+	/// Waits for `task` of job `id` while [`kill_job`](Backend::kill_job) can
+	/// cancel it.
+	async fn follow(&self, id: &str, task: &ArrayTask) -> Result<JobOutcome> {
+		self.running.lock().expect("running mutex poisoned").insert(id.to_string(), task.clone());
+		let outcome = self.wait(id, task).await;
+		self.running.lock().expect("running mutex poisoned").remove(id);
+		outcome
+	}
+
+	/// Waits until `task` of job `id` has left the queue and collects its output.
+	async fn wait(&self, id: &str, task: &ArrayTask) -> Result<JobOutcome> {
+		let mut unknown_polls = 0;
+		loop {
+			tokio::time::sleep(self.poll_interval()).await;
+			let (active, state) = self.task_state(task).await?;
+			if active {
+				continue;
+			}
+			if matches!(state.as_deref(), Some("TIMEOUT" | "DEADLINE")) {
+				return Ok(JobOutcome::TimedOut);
+			}
+			let job_dir = self.scratch.job_dir(id);
+			if let Some(exit_status) = self.read_file(&format!("{}/exit_status", job_dir)).await? {
+				let exit_status = exit_status.trim().parse::<u32>()
+					.with_context(|| format!("Invalid exit status of job {}: {}", id, exit_status.trim()))?;
+				return Ok(JobOutcome::Exited(JobOutput {
+					exit_status,
+					stdout: self.read_file(&format!("{}/stdout", job_dir)).await?.unwrap_or_default(),
+					stderr: self.read_file(&format!("{}/stderr", job_dir)).await?.unwrap_or_default(),
+				}));
+			}
+			match state {
+				Some(state) => anyhow::bail!("Slurm task {}_{} ended as {} without an exit status", task.job_id, task.index, state),
+				None => {
+					// sacct can lag behind squeue for a while
+					unknown_polls += 1;
+					if unknown_polls >= MAX_UNKNOWN_POLLS {
+						anyhow::bail!("Slurm task {}_{} disappeared from squeue and sacct", task.job_id, task.index);
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Parses lines of `<job id>_<index><separator><state>` into states by index.
+/// Lines of pending task ranges such as `123_[4-9]` are skipped.
+fn parse_states(lines: &str, separator: char) -> HashMap<usize, String> {
+	lines.lines()
+		.filter_map(|line| {
+			let (id, state) = line.trim().split_once(separator)?;
+			let index = id.split_once('_')?.1.parse::<usize>().ok()?;
+			// sacct appends details such as `CANCELLED by 1000`
+			let state = state.split_whitespace().next()?;
+			Some((index, state.to_string()))
+		})
+		.collect()
+}
+
+#[async_trait]
+impl Backend for SlurmBackend {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn threads(&self) -> usize {
+		self.config.max_jobs * self.max_job_threads.max(1)
+	}
+
+	/// Slurm places jobs by the memory they request
+	fn memory(&self) -> Option<u64> {
+		None
+	}
+
+	/// `threads` only sizes the budget for `max_jobs` of the largest jobs
+	fn max_slots(&self) -> Option<usize> {
+		Some(self.config.max_jobs)
+	}
+
+	async fn execute(&self, command: &str) -> Result<JobOutput> {
+		self.login.execute(command).await
+	}
+
+	async fn prepare_workdir(&self, workdir: &str) -> Result<()> {
+		self.login.prepare_workdir(workdir).await
+	}
+
+	async fn run_job(&self, job: &JobRun<'_>) -> Result<JobOutcome> {
+		self.stage(job).await?;
+		let task = self.submit(job.id).await?;
+		debug!("Job {} is Slurm task {}_{}", job.id, task.job_id, task.index);
+		self.follow(job.id, &task).await
+	}
+
+	async fn reattach(&self, job: &JobRun<'_>) -> Result<Option<JobOutcome>> {
+		let Some(task) = self.recorded_task(job.id).await? else {
+			return Ok(None);
+		};
+		info!("Reattached to job {} as Slurm task {}_{}", job.id, task.job_id, task.index);
+		self.follow(job.id, &task).await.map(Some)
+	}
+
+	/// A job that was never submitted is not running either
+	async fn is_running(&self, id: &str) -> Result<bool> {
+		match self.recorded_task(id).await? {
+			Some(task) => Ok(self.task_state(&task).await?.0),
+			None => Ok(false),
+		}
+	}
+
+	async fn kill_job(&self, job: &JobRun<'_>) -> Result<()> {
+		self.pending.lock().expect("pending mutex poisoned").retain(|(id, _)| id != job.id);
+		let task = self.running.lock().expect("running mutex poisoned").remove(job.id);
+		if let Some(task) = task {
+			self.run(&format!("scancel {}", shell_quote(&format!("{}_{}", task.job_id, task.index)))).await?;
+		}
+		Ok(())
+	}
+
+	async fn fetch_results(&self, id: &str, destination: &Path) -> Result<()> {
+		self.login.fetch_results(id, destination).await
+	}
+
+	async fn cleanup(&self) -> Result<()> {
+		self.login.cleanup().await
+	}
+
+	async fn reconnect(&self) -> Result<()> {
+		self.login.reconnect().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_states;
+
+	#[test]
+	fn squeue_lines_are_parsed_by_index() {
+		let states = parse_states("1234_0 RUNNING\n1234_7 PENDING\n", ' ');
+		assert_eq!(states.len(), 2);
+		assert_eq!(states[&0], "RUNNING");
+		assert_eq!(states[&7], "PENDING");
+	}
+
+	#[test]
+	fn pending_ranges_and_junk_are_skipped() {
+		let states = parse_states("1234_[3-9] PENDING\n1234_2 RUNNING\n\nslurm_load_jobs error\n1234 RUNNING\n", ' ');
+		assert_eq!(states.len(), 1);
+		assert_eq!(states[&2], "RUNNING");
+	}
+
+	#[test]
+	fn sacct_details_are_dropped() {
+		let states = parse_states("1234_0|COMPLETED\n1234_1|CANCELLED by 1000\n  1234_2|TIMEOUT\n1234_3|\n", '|');
+		assert_eq!(states.len(), 3);
+		assert_eq!(states[&0], "COMPLETED");
+		assert_eq!(states[&1], "CANCELLED");
+		assert_eq!(states[&2], "TIMEOUT");
+	}
+}
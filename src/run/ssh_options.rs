@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Keywords that may be given several times, all values being used.
+const MULTI_VALUED: &[&str] = &["identityfile", "certificatefile", "localforward", "remoteforward", "dynamicforward", "sendenv", "setenv"];
+
+struct Block {
+	/// `None` for a `Match` block, which is never applied
+	patterns: Option<Vec<String>>,
+	options: Vec<(String, String)>,
+}
+
+/// A parsed `~/.ssh/config`.
+///
+/// Follows the OpenSSH rules: keywords are case-insensitive, `Host` patterns
+/// may use `*`, `?` and `!`, and for each keyword the first value obtained
+/// wins. `Match` blocks and `Include` are not evaluated.
+pub struct SshConfig {
+	blocks: Vec<Block>,
+}
+
+/// The options that apply to one host, keyed by lowercase keyword.
+#[derive(Debug, Default)]
+pub struct HostOptions(HashMap<String, Vec<String>>);
+
+impl HostOptions {
+	pub fn get(&self, keyword: &str) -> Option<&str> {
+		self.0.get(&keyword.to_ascii_lowercase())?.first().map(String::as_str)
+	}
+
+	pub fn get_all(&self, keyword: &str) -> &[String] {
+		self.0.get(&keyword.to_ascii_lowercase()).map(Vec::as_slice).unwrap_or_default()
+	}
+}
+
+/// Splits the arguments of an option, honouring double quotes.
+fn split_arguments(s: &str) -> Vec<String> {
+	let mut arguments = Vec::new();
+	let mut current = String::new();
+	let mut in_quotes = false;
+	let mut has_content = false;
+	for c in s.chars() {
+		match c {
+			'"' => {
+				in_quotes = !in_quotes;
+				has_content = true;
+			}
+			c if c.is_whitespace() && !in_quotes => {
+				if has_content {
+					arguments.push(std::mem::take(&mut current));
+					has_content = false;
+				}
+			}
+			c => {
+				current.push(c);
+				has_content = true;
+			}
+		}
+	}
+	if has_content {
+		arguments.push(current);
+	}
+	arguments
+}
+
+/// Matches `text` against a pattern with `*` and `?` wildcards.
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+	match (pattern.first(), text.first()) {
+		(None, None) => true,
+		(Some(b'*'), _) => wildcard_match(&pattern[1..], text) || (!text.is_empty() && wildcard_match(pattern, &text[1..])),
+		(Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+		(Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => wildcard_match(&pattern[1..], &text[1..]),
+		_ => false,
+	}
+}
+
+fn host_matches(patterns: &[String], host: &str) -> bool {
+	let mut matched = false;
+	for pattern in patterns {
+		match pattern.strip_prefix('!') {
+			Some(negated) if wildcard_match(negated.as_bytes(), host.as_bytes()) => return false,
+			Some(_) => {}
+			None => matched |= wildcard_match(pattern.as_bytes(), host.as_bytes()),
+		}
+	}
+	matched
+}
+
+impl SshConfig {
+	pub fn parse(source: &str) -> Self {
+		// Options before the first Host line apply to every host
+		let mut blocks = vec![Block { patterns: Some(vec!["*".to_string()]), options: Vec::new() }];
+		for line in source.lines() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let (keyword, rest) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+				Some(i) => (&line[..i], line[i..].trim_start_matches(|c: char| c.is_whitespace() || c == '=')),
+				None => (line, ""),
+			};
+			let keyword = keyword.to_ascii_lowercase();
+			match keyword.as_str() {
+				"host" => blocks.push(Block { patterns: Some(split_arguments(rest)), options: Vec::new() }),
+				"match" => blocks.push(Block { patterns: None, options: Vec::new() }),
+				_ => {
+					let block = blocks.last_mut().expect("there is always a first block");
+					block.options.push((keyword, rest.to_string()));
+				}
+			}
+		}
+		Self { blocks }
+	}
+
+	pub fn load() -> Option<Self> {
+		let path = home::home_dir()?.join(".ssh/config");
+		let source = fs::read_to_string(path).ok()?;
+		Some(Self::parse(&source))
+	}
+
+	pub fn query(&self, host: &str) -> HostOptions {
+		let mut options: HashMap<String, Vec<String>> = HashMap::new();
+		for block in &self.blocks {
+			let Some(patterns) = &block.patterns else {
+				continue;
+			};
+			if !host_matches(patterns, host) {
+				continue;
+			}
+			for (keyword, value) in &block.options {
+				let value = if keyword == "proxycommand" {
+					value.clone()
+				} else {
+					split_arguments(value).join(" ")
+				};
+				match options.get_mut(keyword) {
+					Some(values) if MULTI_VALUED.contains(&keyword.as_str()) => values.push(value),
+					Some(_) => {}
+					None => {
+						options.insert(keyword.clone(), vec![value]);
+					}
+				}
+			}
+		}
+		HostOptions(options)
+	}
+}
+
+/// Expands `~` and the `%` tokens ssh allows in paths such as `IdentityFile`.
+pub fn expand_path(path: &str, host_name: &str, port: u16, remote_user: &str, local_user: &str) -> PathBuf {
+	let path = path.trim_matches('"');
+	let home = home::home_dir().unwrap_or_default();
+	let mut expanded = String::with_capacity(path.len());
+	let mut chars = path.chars();
+	while let Some(c) = chars.next() {
+		if c != '%' {
+			expanded.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('%') => expanded.push('%'),
+			Some('d') => expanded.push_str(&home.to_string_lossy()),
+			Some('h') => expanded.push_str(host_name),
+			Some('p') => expanded.push_str(&port.to_string()),
+			Some('r') => expanded.push_str(remote_user),
+			Some('u') => expanded.push_str(local_user),
+			Some(other) => {
+				expanded.push('%');
+				expanded.push(other);
+			}
+			None => expanded.push('%'),
+		}
+	}
+	match expanded.strip_prefix("~/") {
+		Some(rest) => home.join(rest),
+		None => PathBuf::from(expanded),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn first_value_wins() {
+		let config = SshConfig::parse("Host web\n  User alice\n  Port 2222\nHost *\n  User nobody\n  Port 22\n  ForwardAgent yes\n");
+		let options = config.query("web");
+		assert_eq!(options.get("User"), Some("alice"));
+		assert_eq!(options.get("port"), Some("2222"));
+		assert_eq!(options.get("ForwardAgent"), Some("yes"));
+		assert_eq!(config.query("db").get("User"), Some("nobody"));
+	}
+
+	#[test]
+	fn options_before_the_first_host_apply_to_all() {
+		let config = SshConfig::parse("User early\nHost web\n  User late\n");
+		assert_eq!(config.query("web").get("User"), Some("early"));
+	}
+
+	#[test]
+	fn identity_files_add_up() {
+		let config = SshConfig::parse("Host web\n  IdentityFile ~/.ssh/web\nHost *\n  IdentityFile ~/.ssh/id_ed25519\n  IdentityFile ~/.ssh/id_rsa\n");
+		assert_eq!(config.query("web").get_all("IdentityFile"), ["~/.ssh/web", "~/.ssh/id_ed25519", "~/.ssh/id_rsa"]);
+		assert_eq!(config.query("db").get_all("IdentityFile"), ["~/.ssh/id_ed25519", "~/.ssh/id_rsa"]);
+		assert!(config.query("db").get_all("CertificateFile").is_empty());
+	}
+
+	#[test]
+	fn host_patterns_use_wildcards_and_negation() {
+		let config = SshConfig::parse("Host node? !node0\n  User compute\nHost *.example.org\n  User example\nHost gpu* cpu*\n  User worker\n");
+		assert_eq!(config.query("node1").get("User"), Some("compute"));
+		assert_eq!(config.query("node0").get("User"), None);
+		assert_eq!(config.query("node10").get("User"), None);
+		assert_eq!(config.query("a.example.org").get("User"), Some("example"));
+		assert_eq!(config.query("example.org").get("User"), None);
+		assert_eq!(config.query("CPU7").get("User"), Some("worker"));
+		// A negated pattern alone matches nothing
+		assert_eq!(SshConfig::parse("Host !web\n  User x\n").query("db").get("User"), None);
+	}
+
+	#[test]
+	fn keywords_take_equals_signs_and_any_case() {
+		let config = SshConfig::parse("HOST web\n  user=alice\n  Port = 2222\n  HostName\t= web.example.org\n");
+		let options = config.query("web");
+		assert_eq!(options.get("User"), Some("alice"));
+		assert_eq!(options.get("Port"), Some("2222"));
+		assert_eq!(options.get("HostName"), Some("web.example.org"));
+	}
+
+	#[test]
+	fn quoted_arguments_keep_their_spaces() {
+		let config = SshConfig::parse("Host \"my host\" other\n  IdentityFile \"~/My Keys/id\"\n  ProxyCommand ssh -W \"%h:%p\" gate\n");
+		let options = config.query("my host");
+		assert_eq!(options.get_all("IdentityFile"), ["~/My Keys/id"]);
+		assert_eq!(options.get("ProxyCommand"), Some("ssh -W \"%h:%p\" gate"));
+		assert_eq!(config.query("other").get_all("IdentityFile"), ["~/My Keys/id"]);
+		assert_eq!(split_arguments(r#"a "b c" "" d"#), ["a", "b c", "", "d"]);
+	}
+
+	#[test]
+	fn match_blocks_are_skipped() {
+		let config = SshConfig::parse("Match host web\n  User matched\nHost web\n  User alice\n# User commented\n");
+		assert_eq!(config.query("web").get("User"), Some("alice"));
+		let config = SshConfig::parse("Host web\n  Port 2222\nMatch all\n  User matched\n");
+		assert_eq!(config.query("web").get("User"), None);
+		assert_eq!(config.query("web").get("Port"), Some("2222"));
+	}
+
+	#[test]
+	fn paths_expand_tokens() {
+		let home = home::home_dir().unwrap_or_default();
+		assert_eq!(expand_path("~/.ssh/%h_%p_%r", "web", 22, "alice", "bob"), home.join(".ssh/web_22_alice"));
+		assert_eq!(expand_path("/keys/%u%%", "web", 22, "alice", "bob"), PathBuf::from("/keys/bob%"));
+	}
+}
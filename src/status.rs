@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use anyhow::Result;
+use futures::future::join_all;
+use spdlog::prelude::*;
+use crate::run::backend::{Backend, Scratch};
+use crate::run::completion::JobStatus;
+use crate::run::config_file::{Backend as BackendKind, Config};
+use crate::run::journal::Replay;
+use crate::run::local::LocalNode;
+use crate::run::node::{Node, NodeCommon};
+use crate::run::nodes::Nodes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Running,
+    /// A detached job that exited or is gone, not collected yet
+    Ended,
+    /// A detached job the journal saw start, on a host that was not polled
+    Unknown,
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+/// Number of jobs in each state.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    pending: usize,
+    running: usize,
+    ended: usize,
+    unknown: usize,
+    succeeded: usize,
+    failed: usize,
+    timed_out: usize,
+}
+
+impl Counts {
+    fn add(&mut self, state: State) {
+        match state {
+            State::Pending => self.pending += 1,
+            State::Running => self.running += 1,
+            State::Ended => self.ended += 1,
+            State::Unknown => self.unknown += 1,
+            State::Succeeded => self.succeeded += 1,
+            State::Failed => self.failed += 1,
+            State::TimedOut => self.timed_out += 1,
+        }
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pending {:>5}  running {:>5}  ended {:>5}  unknown {:>5}  succeeded {:>5}  failed {:>5}  timed out {:>5}",
+            self.pending, self.running, self.ended, self.unknown, self.succeeded, self.failed, self.timed_out)
+    }
+}
+
+/// A backend to poll, or why it cannot be, and the ids of the jobs to poll
+type HostJobs<'a> = (Result<Box<dyn Backend>>, Vec<&'a str>);
+
+/// Asks the backend of `config` whether the jobs the journal saw start but
+/// not end still run. Jobs on this machine are always polled; SSH hosts and
+/// the Slurm login node only if `connect` is given. Jobs on hosts that are
+/// not polled or cannot be reached are left out.
+async fn poll_running(config: &Config, scratch: &Scratch, running: &HashMap<String, String>, connect: Option<NodeCommon>) -> HashMap<String, State> {
+    let mut ids_by_host: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (id, host) in running {
+        ids_by_host.entry(host.as_str()).or_default().push(id.as_str());
+    }
+    let backends: Vec<HostJobs> = match (config.backend, connect) {
+        (BackendKind::Local, _) => {
+            let ids = ids_by_host.into_values().flatten().collect();
+            vec![(LocalNode::new(scratch.clone()).map(|node| Box::new(node) as _), ids)]
+        }
+        (BackendKind::Ssh, Some(common)) => {
+            let common = &common;
+            join_all(ids_by_host.into_iter().map(|(host, ids)| async move {
+                (Node::try_new(common, host, scratch.clone()).await.map(|node| Box::new(node) as _), ids)
+            })).await
+        }
+        (BackendKind::Slurm, Some(common)) => {
+            let ids = ids_by_host.into_values().flatten().collect();
+            let backend = match &config.slurm {
+                Some(slurm) => Nodes::slurm(slurm.clone(), common, scratch, 1).await
+                    .and_then(|mut nodes| nodes.nodes.pop().ok_or_else(|| anyhow::anyhow!("no Slurm backend"))),
+                None => Err(anyhow::anyhow!("backend = \"slurm\" needs a [slurm] section")),
+            };
+            vec![(backend, ids)]
+        }
+        (BackendKind::Ssh | BackendKind::Slurm, None) => Vec::new(),
+    };
+
+    let mut states = HashMap::new();
+    for (backend, ids) in backends {
+        let backend = match backend {
+            Ok(backend) => backend,
+            Err(err) => {
+                warn!("Cannot poll {} jobs that were running\n{:#}", ids.len(), err);
+                continue;
+            }
+        };
+        let polls = join_all(ids.iter().map(|id| backend.is_running(id))).await;
+        for (id, poll) in ids.into_iter().zip(polls) {
+            let state = match poll {
+                Ok(true) => State::Running,
+                Ok(false) => State::Ended,
+                Err(err) => {
+                    warn!("Failed to poll job {} on {}\n{:#}", id, backend.name(), err);
+                    State::Unknown
+                }
+            };
+            states.insert(id.to_string(), state);
+        }
+    }
+    states
+}
+
+/// Prints how far the jobs of `config` in `<output>/<name>` have got. Jobs
+/// that have a completion marker are finished; of the others, those that the
+/// journal saw dispatched but not end are running and the rest are pending.
+/// Detached jobs outlive a controller that crashed, so the journal alone
+/// cannot tell whether they still run: their backend is asked, see
+/// [`poll_running`], and the state of those it is not asked about is unknown.
+pub async fn status(config: &Config, output: &Path, connect: Option<NodeCommon>) -> Result<()> {
+    let results_path = output.join(&config.name);
+    let replay = Replay::load(&results_path)?;
+    let scratch = Scratch::for_config(config);
+    let outlives_controller = config.detached || config.backend == BackendKind::Slurm;
+    let polled = match outlives_controller {
+        true => poll_running(config, &scratch, &replay.running, connect).await,
+        false => HashMap::new(),
+    };
+    let mut permutations: Vec<_> = config.get_arguments_permutations().into_values().collect();
+    permutations.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut total = Counts::default();
+    let mut by_value: BTreeMap<(String, String), Counts> = BTreeMap::new();
+    let mut by_host: BTreeMap<String, Counts> = BTreeMap::new();
+    let mut failed = Vec::new();
+    for permutation in &permutations {
+        let state = match JobStatus::from_result_dir(&results_path.join(&permutation.id)) {
+            JobStatus::Succeeded => State::Succeeded,
+            JobStatus::Failed => State::Failed,
+            JobStatus::TimedOut => State::TimedOut,
+            JobStatus::Incomplete if replay.running.contains_key(&permutation.id) => match outlives_controller {
+                true => polled.get(&permutation.id).copied().unwrap_or(State::Unknown),
+                false => State::Running,
+            },
+            JobStatus::Incomplete => State::Pending,
+        };
+        total.add(state);
+        for argument in &permutation.arguments {
+            by_value.entry(argument.clone()).or_default().add(state);
+        }
+        if let Some(host) = replay.hosts.get(&permutation.id) {
+            by_host.entry(host.clone()).or_default().add(state);
+        }
+        if matches!(state, State::Failed | State::TimedOut) {
+            failed.push((permutation.id.as_str(), state));
+        }
+    }
+
+    println!("{}: {} jobs", config.name, permutations.len());
+    println!("  {}", total);
+    if total.unknown > 0 {
+        println!("  {} jobs were running detached when the journal was last written; their state is unknown (pass --check to poll their hosts)", total.unknown);
+    }
+    if total.ended > 0 {
+        println!("  {} detached jobs have ended; run again to collect their results", total.ended);
+    }
+    println!();
+    println!("By parameter value:");
+    let width = by_value.keys().map(|(name, value)| name.len() + value.len() + 1).max().unwrap_or(0);
+    for ((name, value), counts) in &by_value {
+        println!("  {:<width$}  {}", format!("{}={}", name, value), counts);
+    }
+    if !by_host.is_empty() {
+        println!();
+        println!("By host (of the last attempt):");
+        let width = by_host.keys().map(String::len).max().unwrap_or(0);
+        for (host, counts) in &by_host {
+            println!("  {:<width$}  {}", host, counts);
+        }
+    }
+    if !failed.is_empty() {
+        println!();
+        println!("Failed jobs:");
+        for (id, state) in failed {
+            let suffix = if state == State::TimedOut { " (timed out)" } else { "" };
+            println!("  {}{}", id, suffix);
+        }
+    }
+    Ok(())
+}
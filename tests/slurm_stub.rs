@@ -0,0 +1,85 @@
+//! Runs a small grid through the Slurm backend, with the stand-ins for
+//! sbatch, squeue and sacct from `scripts/slurm-stub` first in PATH.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+const SCRIPT: &str = r#"#!/bin/sh
+echo "args: $@" > out.txt
+[ "$2" = "--b=2" ] && exit 3
+[ "$1" = "--a=slow" ] && sleep 30
+exit 0
+"#;
+
+fn config(dir: &Path) -> String {
+    format!(r#"
+name = "grid"
+backend = "slurm"
+workdir = "{workdir}"
+executable = "run.sh"
+repeat = 1
+threads_per_task = 1
+timeout_overrides = [{{ arguments = {{ a = "slow" }}, timeout = 2 }}]
+
+[slurm]
+scratch_dir = "{scratch}"
+poll_interval = 1
+max_jobs = 2
+
+[arguments]
+a = ["fast", "slow"]
+b = [1, 2]
+"#, workdir = dir.join("wd").display(), scratch = dir.join("scratch").display())
+}
+
+fn mner(dir: &Path, args: &[&str]) -> std::process::Output {
+    let stubs = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts/slurm-stub");
+    let path = format!("{}:{}", stubs.display(), std::env::var("PATH").unwrap_or_default());
+    Command::new(env!("CARGO_BIN_EXE_MNER"))
+        .args(args)
+        .current_dir(dir)
+        .env("PATH", path)
+        .env("SLURM_STUB_DIR", dir.join("state"))
+        .output()
+        .expect("failed to start MNER")
+}
+
+fn marker(results: &Path, id: &str) -> String {
+    fs::read_to_string(results.join(id).join("complete"))
+        .unwrap_or_else(|err| panic!("{} has no complete marker: {}", id, err))
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn grid_runs_through_sbatch_and_is_collected() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir_all(dir.join("wd")).unwrap();
+    fs::write(dir.join("wd/run.sh"), SCRIPT).unwrap();
+    fs::set_permissions(dir.join("wd/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(dir.join("grid.toml"), config(dir)).unwrap();
+
+    let output = mner(dir, &["run", "grid.toml", "results"]);
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let results = dir.join("results/grid");
+    assert_eq!(marker(&results, "a=fast-b=1_0"), "succeeded");
+    assert_eq!(marker(&results, "a=fast-b=2_0"), "failed");
+    assert_eq!(marker(&results, "a=slow-b=1_0"), "timed_out");
+    assert_eq!(marker(&results, "a=slow-b=2_0"), "failed");
+    let out = fs::read_to_string(results.join("a=fast-b=1_0/out.txt")).unwrap();
+    assert_eq!(out.trim(), "args: --a=fast --b=1");
+
+    // Every task went through sbatch, at most max_jobs tasks per array
+    let submitted: u32 = fs::read_to_string(dir.join("state/next")).unwrap().trim().parse().unwrap();
+    assert!(submitted > 1000);
+
+    // Finished jobs are skipped by the next run
+    let output = mner(dir, &["run", "grid.toml", "results", "--skip", "all"]);
+    assert!(output.status.success(), "rerun failed: {}", String::from_utf8_lossy(&output.stderr));
+    let resubmitted: u32 = fs::read_to_string(dir.join("state/next")).unwrap().trim().parse().unwrap();
+    assert_eq!(resubmitted, submitted);
+}